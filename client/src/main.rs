@@ -1,46 +1,142 @@
+use clap::Parser;
 use crossterm::execute;
 use crossterm::terminal::{Clear, ClearType};
+use protocol::{
+    read_frame, write_frame, Capabilities, Envelope, ErrorCode, Found, Fuzziness, Hello, Request,
+    RequestId, Response, SearchMode, SearchOptions, SearchOutput, SearchStatus, Welcome,
+    CONNECTION_ID,
+};
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{self, stdout, Read, Write};
 use std::net::TcpStream;
-use std::sync::Arc;
+use std::sync::{Arc, LazyLock};
+use std::thread;
 use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
 
 // constants
 
-const SERVER_ADDR: &str = "127.0.0.1:5000";
+// from --server or MYGOOGLE_SERVER
+static SERVER_ADDR: LazyLock<String> = LazyLock::new(|| Args::parse().server);
+// the connection kept open between commands
+static POOL: LazyLock<Pool> = LazyLock::new(|| Pool::new(&SERVER_ADDR));
+// the searches Ctrl-C cancels, none between commands so Ctrl-C quits
+static CANCELLABLE: std::sync::Mutex<Option<Cancellable>> = std::sync::Mutex::new(None);
 const BUFFER_SIZE: usize = 16 * 1024;
-const ACK: &[u8] = b"OK";
-// server command map
-const UPLOAD_CMD: u8 = 1;
-const SEARCH_CMD: u8 = 2;
-const DELETE_CMD: u8 = 3;
-const LIST_CMD: u8 = 4;
+// sent to the server in the handshake
+const BUILD: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+const CAPABILITIES: Capabilities = Capabilities::REGEX
+    .union(Capabilities::FUZZY)
+    .union(Capabilities::EXACT_ACCENTS);
 
 #[derive(Debug)]
 struct FileState {
     name: String,
     size: u64,
     bytes_read: u64,
+    // BM25 relevance sent by the server, 0 for unranked results
+    score: f64,
     occurrences: Vec<String>,
+    // sent instead of the occurrences when the search only asked for counts
+    // or files
+    matched: bool,
+    count: Option<u64>,
+    // the numbered lines of the matches and around them, true for the
+    // matched ones, with context lines only
+    lines: BTreeMap<u64, (bool, String)>,
 }
 
 struct SearchState {
     progress: String,
     files: Vec<FileState>,
     last_update_lines: u32,
+    // the matches come with their whole lines
+    context_lines: bool,
+    output: SearchOutput,
 }
 
 impl SearchState {
-    fn new() -> Self {
+    fn new(options: &SearchOptions) -> Self {
         Self {
             files: Vec::new(),
             progress: "0.0".to_string(),
             last_update_lines: 0,
+            context_lines: options.context_lines.is_some(),
+            output: options.output,
         }
     }
 
+    // takes in a result of the search, the other responses are given back
+    fn record(&mut self, response: Response) -> Option<Response> {
+        match response {
+            Response::Searching { path, size } => {
+                let file = FileState {
+                    name: path,
+                    size,
+                    bytes_read: 0,
+                    score: 0.0,
+                    occurrences: Vec::new(),
+                    matched: false,
+                    count: None,
+                    lines: BTreeMap::new(),
+                };
+                self.files.push(file);
+            }
+            Response::Found(found) => {
+                self.add_occurrence(&found);
+                self.sort_score();
+            }
+            Response::Matched { path, score, count } => {
+                if let Some(file) = self.files.iter_mut().find(|file| file.name == path) {
+                    file.matched = true;
+                    file.score = score;
+                    file.count = count;
+                }
+                self.sort_score();
+            }
+            Response::Progress { path, bytes_read } => {
+                // if not already created create file state
+                let file = self.files.iter_mut().find(|file| file.name == path);
+                match file {
+                    Some(file) => {
+                        file.bytes_read = bytes_read;
+                    }
+                    None => {
+                        let file = FileState {
+                            name: path,
+                            size: 0,
+                            bytes_read,
+                            score: 0.0,
+                            occurrences: Vec::new(),
+                            matched: false,
+                            count: None,
+                            lines: BTreeMap::new(),
+                        };
+                        self.files.push(file);
+                    }
+                }
+                self.update_progress();
+            }
+            response => return Some(response),
+        }
+        None
+    }
+
+    // a single line about the search, for the multi command
+    fn summary(&self) -> String {
+        let occurrences: u64 = self.files.iter().map(FileState::found).sum();
+        if self.output == SearchOutput::FilesWithMatches {
+            return format!("{}%, {} files matched", self.progress, occurrences);
+        }
+        format!(
+            "{}%, {} files, {} found",
+            self.progress,
+            self.files.len(),
+            occurrences
+        )
+    }
+
     fn update_progress(&mut self) {
         let total_size = self.files.iter().map(|file| file.size).sum::<u64>();
         let total_bytes_read = self.files.iter().map(|file| file.bytes_read).sum::<u64>();
@@ -48,18 +144,29 @@ impl SearchState {
         self.progress = format!("{:.5}", progress);
     }
 
-    fn add_occurrence(&mut self, file_name: &str, occurrence: &str, snippet: &str) {
+    fn add_occurrence(&mut self, found: &Found) {
         for file in &mut self.files {
-            if file.name == file_name.to_string() {
-                file.occurrences
-                    .push(occurrence.to_string() + " - " + snippet);
+            if file.name == found.path {
+                file.score = found.score;
+                let mut occurrence =
+                    format!("{}, line {}:{}", found.byte, found.line, found.column);
+                if let Some((first, last)) = found.positions {
+                    occurrence += &format!(" (words {}-{})", first, last);
+                }
+                occurrence += &format!(" [{}]", found.matched);
+                // keep every occurrence in a single line
+                let snippet = found.snippet.replace(['\r', '\n', '\t'], " ");
+                file.occurrences.push(occurrence + " - " + &snippet);
+                if self.context_lines {
+                    add_lines(file, found);
+                }
                 return;
             }
         }
     }
-    fn sort_occ(&mut self) {
+    fn sort_score(&mut self) {
         self.files
-            .sort_by(|a, b| b.occurrences.len().cmp(&a.occurrences.len()));
+            .sort_by(|a, b| b.score.total_cmp(&a.score).then(b.found().cmp(&a.found())));
     }
 
     fn display_short(&mut self) {
@@ -73,7 +180,14 @@ impl SearchState {
         println!("Search progress: {:.5}%", self.progress);
         line_counter += 1;
         for file in &self.files {
-            println!("At File: {}, {} times", file.name, file.occurrences.len());
+            if self.output == SearchOutput::FilesWithMatches {
+                if file.matched {
+                    println!("At File: {}", file.name);
+                    line_counter += 1;
+                }
+                continue;
+            }
+            println!("At File: {}, {} times", file.name, file.found());
             line_counter += 1;
         }
         self.last_update_lines = line_counter;
@@ -90,17 +204,32 @@ impl SearchState {
         println!("Search progress: {:.5}%", self.progress);
         line_counter += 1;
         for file in &self.files {
-            println!("At File: {}, {} times", file.name, file.occurrences.len());
+            if self.output == SearchOutput::FilesWithMatches {
+                // only the files with matches, without the others searched
+                if file.matched {
+                    println!("At File: {}, score {:.4}", file.name, file.score);
+                    line_counter += 1;
+                }
+                continue;
+            }
+            println!(
+                "At File: {}, {} times, score {:.4}",
+                file.name,
+                file.found(),
+                file.score
+            );
             line_counter += 1;
-            let mut occ_counter = 0;
-            for occurrence in &file.occurrences {
+            if self.context_lines {
+                line_counter += display_lines(file);
+                continue;
+            }
+            for (occ_counter, occurrence) in file.occurrences.iter().enumerate() {
                 if occ_counter > 10 {
                     println!("  ...");
                     line_counter += 1;
                     break;
                 };
                 println!("  At byte: {}", occurrence);
-                occ_counter += 1;
                 line_counter += 1;
             }
         }
@@ -108,6 +237,62 @@ impl SearchState {
     }
 }
 
+impl FileState {
+    // occurrences of the file, counted by the server in the count output,
+    // a matched file counts once in the files output
+    fn found(&self) -> u64 {
+        match self.count {
+            Some(count) => count,
+            None if self.matched => 1,
+            None => self.occurrences.len() as u64,
+        }
+    }
+}
+
+// the lines of a match, a match spanning lines has them all in its snippet
+fn add_lines(file: &mut FileState, found: &Found) {
+    let before = found.lines_before.iter().rev();
+    for (number, line) in (1..found.line).rev().zip(before) {
+        file.lines.entry(number).or_insert((false, line.clone()));
+    }
+    let mut number = found.line;
+    for line in found.snippet.split('\n') {
+        // a line shown as context before is a matched one now
+        file.lines.insert(number, (true, line.to_string()));
+        number += 1;
+    }
+    for line in &found.lines_after {
+        file.lines.entry(number).or_insert((false, line.clone()));
+        number += 1;
+    }
+}
+
+// like grep -n -C, ":" after the number of a matched line, "-" after the
+// others and "--" between groups of lines, gives back the lines printed
+fn display_lines(file: &FileState) -> u32 {
+    let mut line_counter = 0;
+    let mut matched_lines = 0;
+    let mut previous = None;
+    for (&number, (matched, line)) in &file.lines {
+        if *matched {
+            if matched_lines > 10 {
+                println!("  ...");
+                return line_counter + 1;
+            }
+            matched_lines += 1;
+        }
+        if previous.is_some_and(|previous| previous + 1 != number) {
+            println!("  --");
+            line_counter += 1;
+        }
+        let separator = if *matched { ':' } else { '-' };
+        println!("  {}{}{}", number, separator, line);
+        line_counter += 1;
+        previous = Some(number);
+    }
+    line_counter
+}
+
 async fn handle_command(args: Vec<String>) -> Result<(), String> {
     match args[0].as_str() {
         "help" => {
@@ -118,9 +303,32 @@ async fn handle_command(args: Vec<String>) -> Result<(), String> {
             println!("  clear - clear the screen");
             println!("  quit - quit the program");
             println!("  upload <file> - upload file to server");
-            println!("  search <term> - search for term in files");
+            println!("  search [options] <query> - search for the query in files");
+            println!("      rust tcp - files with both words");
+            println!("      rust OR go - files with any of the words");
+            println!("      rust -java - files with rust but without java");
+            println!("      \"tcp socket\" - files with the exact phrase");
+            println!("      socket NEAR/5 timeout - both words at most 5 words apart");
+            println!("      --literal - scan the files for the text as typed, accents included");
+            println!("      --regex - scan the files for a regular expression, accents included");
+            println!("      --fuzzy[=<1|2>] - also match indexed words with typos");
+            println!("      --exact-accents - \"acao\" doesn't match \"ação\" in the index");
+            println!("      --case-sensitive - \"Rust\" doesn't match \"rust\"");
+            println!("      --whole-word - \"art\" doesn't match \"start\"");
+            println!("      --context=<bytes> - text shown around each match");
+            println!("      --lines[=<n>] - whole matched lines with n lines around them");
+            println!("      --count - only how many times each file matches");
+            println!("      --files - only the files with a match");
+            println!("      --timeout=<ms> - stop the search after this long");
+            println!("      --limit=<files> - only the best files");
+            println!("      --max-per-file=<n> - only the first occurrences of each file");
+            println!(
+                "      --before=<bytes>, --after=<bytes> - text shown before/after each match"
+            );
             println!("  delete <file> - delete file from server");
             println!("  list - list files on server");
+            println!("  info - show the server version and features");
+            println!("  multi <command> ; <command> ... - run searches and uploads at once");
             println!("  test <n_requests> <full_duration> <search_term> - test the server");
             Ok(())
         }
@@ -165,99 +373,73 @@ async fn handle_command(args: Vec<String>) -> Result<(), String> {
             if args.len() < 2 {
                 return Err("No file specified".to_string());
             }
-            if !std::path::Path::new(&args[1]).exists() {
-                return Err(format!("File does not exist: {}", args[1]));
-            }
-            let mut stream = TcpStream::connect(SERVER_ADDR).unwrap();
-            if !stream.peer_addr().is_ok() {
-                return Err(format!("Error connecting to server: {}", SERVER_ADDR));
-            }
-            send_command(&mut stream, UPLOAD_CMD);
-            send_message(&mut stream, args[1].clone().as_str());
+            let request = upload_request(&args[1])?;
+            let mut session = session()?;
+            let id = session.send(request).map_err(|e| e.to_string())?;
 
-            if let Err(e) = wait_for_ack(&mut stream) {
-                return Err(format!("Failed to receive ACK1: {}", e));
-            }
+            wait_for_ack(&mut session, id).map_err(|e| e.to_string())?;
 
-            send_file(&mut stream, args[1].clone());
+            send_file(
+                args[1].clone(),
+                |chunk| session.send_as(id, Request::Data(chunk.to_vec())),
+                true,
+            )
+            .map_err(|e| e.to_string())?;
 
-            if let Err(e) = wait_for_ack(&mut stream) {
-                return Err(format!("Failed to receive ACK2: {}", e));
-            } else {
-                Ok(())
+            match session.recv(id) {
+                Ok(Response::Indexed { words }) => {
+                    println!("Uploaded {}, {} words indexed", args[1], words);
+                    POOL.put(session);
+                    Ok(())
+                }
+                Ok(Response::Error { code, message }) => {
+                    Err(server_error(code, message).to_string())
+                }
+                Ok(response) => Err(format!("Unexpected response: {:?}", response)),
+                Err(e) => Err(format!("Failed to receive response: {}", e)),
             }
         }
         "search" => {
-            let mut stream = TcpStream::connect(SERVER_ADDR).expect("Failed to connect");
-            send_command(&mut stream, SEARCH_CMD);
+            let (query, search_options) = parse_search(&args[1..])?;
+            let mut session = session()?;
+            let required = search_options.capabilities();
+            if !session.welcome.capabilities.contains(required) {
+                POOL.put(session);
+                return Err(format!("The server doesn't support {}", required));
+            }
 
-            let search_string = args[1].clone();
-            send_message(&mut stream, search_string.as_str());
+            let mut search_state = SearchState::new(&search_options);
+            let request = Request::Search {
+                query,
+                options: search_options,
+            };
+            let id = session.send(request).map_err(|e| e.to_string())?;
 
-            if let Err(e) = wait_for_ack(&mut stream) {
-                return Err(format!("Failed to receive ACK1: {}", e));
-            }
+            wait_for_ack(&mut session, id).map_err(|e| e.to_string())?;
+            // the loop below only reads, Ctrl-C writes the Cancel frame
+            let writer = session.stream.try_clone().map_err(|e| e.to_string())?;
+            let _interrupt = cancel_on_interrupt(Arc::new(std::sync::Mutex::new(writer)), vec![id]);
             // Enables raw mode to control the cursor better
             let start_time = Instant::now();
-            let mut search_state = SearchState::new();
 
             loop {
-                match recv_message(&mut stream) {
-                    Ok(message) => {
-                        if message.starts_with("searching: ") {
-                            let mut params = message.replace("searching: ", "");
-                            let mut parts = params.split(", ");
-                            let file_name = parts.next().unwrap();
-                            let file_size = parts.next().unwrap().parse::<u64>().unwrap();
-                            let file = FileState {
-                                name: file_name.to_string(),
-                                size: file_size,
-                                bytes_read: 0,
-                                occurrences: Vec::new(),
-                            };
-                            search_state.files.push(file);
-                        } else if message.starts_with("found:") {
-                            // "found: {}, {}" format
-                            let mut params = message.replace("found: ", "");
-                            let mut parts = params.split(", ");
-                            let file_name = parts.next().unwrap();
-                            let byte = parts.next().unwrap();
-                            let snippet = parts.next().unwrap();
-                            // // find file by name
-                            search_state.add_occurrence(file_name, byte, snippet);
-                            search_state.sort_occ();
-                        } else if message.starts_with("update:") {
-                            // if not already created create file state
-                            let params = message.replace("update: ", "");
-                            let mut parts = params.split(", ");
-                            let file_name = parts.next().unwrap();
-                            let bytes_read = parts.next().unwrap().parse::<u64>().unwrap();
-                            let mut file = search_state
-                                .files
-                                .iter_mut()
-                                .find(|file| file.name == file_name);
-                            match file {
-                                Some(file) => {
-                                    file.bytes_read = bytes_read;
-                                }
-                                None => {
-                                    let file = FileState {
-                                        name: file_name.to_string(),
-                                        size: 0,
-                                        bytes_read,
-                                        occurrences: Vec::new(),
-                                    };
-                                    search_state.files.push(file);
-                                }
-                            }
-
-                            search_state.update_progress();
-                            search_state.display_short();
-                        } else if message.starts_with("done:") {
-                            let elapsed_time = start_time.elapsed();
-                            search_state.display();
-                            println!("Search completed in {:.2?}.", elapsed_time);
-                            break;
+                match session.recv(id) {
+                    Ok(Response::Error { code, message }) => {
+                        println!("error: {}", server_error(code, message))
+                    }
+                    Ok(Response::Done { status, .. }) => {
+                        let elapsed_time = start_time.elapsed();
+                        search_state.display();
+                        println!("Search {} in {:.2?}.", status_text(status), elapsed_time);
+                        POOL.put(session);
+                        break;
+                    }
+                    Ok(response) => {
+                        let progress = matches!(response, Response::Progress { .. });
+                        match search_state.record(response) {
+                            None if progress => search_state.display_short(),
+                            None => (),
+                            Some(response) => println!("Unexpected response: {:?}", response),
                         }
                     }
                     Err(e) => {
@@ -268,50 +450,80 @@ async fn handle_command(args: Vec<String>) -> Result<(), String> {
             }
             Ok(())
         }
-        "delete" => {
-            let mut stream = TcpStream::connect(SERVER_ADDR).unwrap();
-            if !stream.peer_addr().is_ok() {
-                return Err(format!("Error connecting to server: {}", SERVER_ADDR));
-            }
-            send_command(&mut stream, DELETE_CMD);
-            send_message(&mut stream, args[1].clone().as_str());
-
-            if let Err(e) = wait_for_ack(&mut stream) {
-                return Err(format!("Failed to receive ACK1: {}", e));
+        "multi" => {
+            let commands: Vec<&[String]> = args[1..]
+                .split(|arg| arg == ";")
+                .filter(|command| !command.is_empty())
+                .collect();
+            if commands.is_empty() {
+                return Err("No commands specified".to_string());
             }
-
-            if let Err(e) = wait_for_ack(&mut stream) {
-                return Err(format!("Failed to receive ACK2: {}", e));
-            } else {
-                Ok(())
+            let session = session()?;
+            let mut jobs = Vec::new();
+            for command in commands {
+                let (request, job) = match command[0].as_str() {
+                    "search" => {
+                        let (query, options) = parse_search(&command[1..])?;
+                        let required = options.capabilities();
+                        if !session.welcome.capabilities.contains(required) {
+                            return Err(format!("The server doesn't support {}", required));
+                        }
+                        let job = Job::Search(SearchState::new(&options));
+                        (Request::Search { query, options }, job)
+                    }
+                    "upload" if command.len() > 1 => (
+                        upload_request(&command[1])?,
+                        Job::Upload(command[1].clone()),
+                    ),
+                    "upload" => return Err("No file specified".to_string()),
+                    other => return Err(format!("multi only runs search and upload: {}", other)),
+                };
+                jobs.push((command.join(" "), request, job));
             }
+            run_jobs(session, jobs).map_err(|e| e.to_string())
         }
-        "list" => {
-            let mut stream = TcpStream::connect(SERVER_ADDR).unwrap();
-            if !stream.peer_addr().is_ok() {
-                return Err(format!("Error connecting to server: {}", SERVER_ADDR));
+        "delete" => {
+            if args.len() < 2 {
+                return Err("No file specified".to_string());
             }
-            send_command(&mut stream, LIST_CMD);
+            let mut session = session()?;
+            let request = Request::Delete {
+                name: args[1].clone(),
+            };
+            let id = session.send(request).map_err(|e| e.to_string())?;
 
-            if let Err(e) = wait_for_ack(&mut stream) {
-                return Err(format!("Failed to receive ACK1: {}", e));
-            }
+            wait_for_ack(&mut session, id).map_err(|e| e.to_string())?;
+            POOL.put(session);
+            println!("Deleted {}", args[1]);
+            Ok(())
+        }
+        "list" => {
+            let mut session = session()?;
+            let id = session.send(Request::List).map_err(|e| e.to_string())?;
 
-            loop {
-                match recv_message(&mut stream) {
-                    Ok(message) => {
-                        if message.starts_with("done:") {
-                            break;
-                        } else {
-                            println!("{}", message);
-                        }
-                    }
-                    Err(e) => {
-                        println!("Error: {}", e);
-                        break;
+            match session.recv(id) {
+                Ok(Response::Files { names }) => {
+                    for name in names {
+                        println!("file: {}", name);
                     }
+                    POOL.put(session);
+                    Ok(())
+                }
+                Ok(Response::Error { code, message }) => {
+                    Err(server_error(code, message).to_string())
                 }
+                Ok(response) => Err(format!("Unexpected response: {:?}", response)),
+                Err(e) => Err(format!("Failed to receive response: {}", e)),
             }
+        }
+        "info" => {
+            // the handshake tells everything, no request is needed
+            let session = session()?;
+            let welcome = &session.welcome;
+            println!("Server: {} at {}", welcome.build, *SERVER_ADDR);
+            println!("Protocol version: {}", welcome.version);
+            println!("Features: {}", welcome.capabilities);
+            POOL.put(session);
             Ok(())
         }
         "test" => {
@@ -324,47 +536,317 @@ async fn handle_command(args: Vec<String>) -> Result<(), String> {
             test(n_requests, full_duration, search_term).await;
             Ok(())
         }
-        _ => {
-            return Err(format!("Unknown command: {}", args[0]));
+        _ => Err(format!("Unknown command: {}", args[0])),
+    }
+}
+
+// the query and options of a search command
+fn parse_search(args: &[String]) -> Result<(String, SearchOptions), String> {
+    let (options, query_args): (Vec<String>, Vec<String>) =
+        args.iter().cloned().partition(|arg| arg.starts_with("--"));
+    let mut search_options = SearchOptions::default();
+    for option in options {
+        match option.as_str() {
+            "--literal" => search_options.mode = SearchMode::Literal,
+            "--regex" => search_options.mode = SearchMode::Regex,
+            "--fuzzy" => search_options.fuzzy = Fuzziness::Auto,
+            "--exact-accents" => search_options.exact_accents = true,
+            "--case-sensitive" => search_options.case_sensitive = true,
+            "--whole-word" => search_options.whole_word = true,
+            "--lines" => search_options.context_lines = Some(0),
+            "--count" => search_options.output = SearchOutput::Count,
+            "--files" => search_options.output = SearchOutput::FilesWithMatches,
+            _ => match option.split_once('=') {
+                Some(("--lines", lines)) => {
+                    search_options.context_lines = Some(parse_number(&option, lines)?)
+                }
+                Some(("--limit", files)) => {
+                    search_options.limit = Some(parse_number(&option, files)?)
+                }
+                Some(("--max-per-file", n)) => {
+                    search_options.max_occurrences_per_file = Some(parse_number(&option, n)?)
+                }
+                Some(("--timeout", ms)) => {
+                    let ms = parse_number(&option, ms)? as u64;
+                    search_options.deadline = Some(Duration::from_millis(ms));
+                }
+                Some(("--fuzzy", edits)) => {
+                    search_options.fuzzy = match parse_number(&option, edits)? {
+                        0 => Fuzziness::Off,
+                        edits => Fuzziness::Max(edits),
+                    }
+                }
+                Some(("--before", bytes)) => search_options.before = parse_number(&option, bytes)?,
+                Some(("--after", bytes)) => search_options.after = parse_number(&option, bytes)?,
+                Some(("--context", bytes)) => {
+                    search_options.before = parse_number(&option, bytes)?;
+                    search_options.after = search_options.before;
+                }
+                _ => return Err(format!("Unknown search option: {}", option)),
+            },
         }
     }
+    if query_args.is_empty() {
+        return Err("No search query specified".to_string());
+    }
+
+    // a regex is sent as typed, quotes included
+    let query = if search_options.mode == SearchMode::Regex {
+        query_args.join(" ")
+    } else {
+        join_query(&query_args)
+    };
+    Ok((query, search_options))
 }
 
-fn send_message(stream: &mut TcpStream, message: &str) -> io::Result<()> {
-    let message_len = message.len();
-    let message_len_bytes = message_len.to_be_bytes();
-    stream.write_all(&message_len_bytes)?;
-    stream.flush()?;
-    stream.write_all(message.as_bytes())?;
-    stream.flush()?;
-    Ok(())
+// the upload of a local file, before its data
+fn upload_request(path: &str) -> Result<Request, String> {
+    if !std::path::Path::new(path).exists() {
+        return Err(format!("File does not exist: {}", path));
+    }
+    // the server stores the file under its name, without the directories
+    let name = match std::path::Path::new(path).file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => return Err(format!("Not a file: {}", path)),
+    };
+    let size = std::fs::metadata(path).map_err(|e| e.to_string())?.len();
+    Ok(Request::Upload { name, size })
 }
 
-fn send_command(stream: &mut TcpStream, command: u8) -> io::Result<()> {
-    match stream.write(&[command]) {
-        Ok(_) => (),
-        Err(e) => return Err(e),
+// a request of the multi command
+enum Job {
+    Search(SearchState),
+    // the local path of the file
+    Upload(String),
+}
+
+// what is known of a request of the multi command
+struct Running {
+    id: RequestId,
+    label: String,
+    job: Job,
+    acked: bool,
+    // the error that ended the request, or its outcome
+    status: String,
+    done: bool,
+}
+
+impl Running {
+    fn line(&self) -> String {
+        let state = match &self.job {
+            Job::Search(_) if !self.status.is_empty() => self.status.clone(),
+            Job::Search(search_state) => search_state.summary(),
+            Job::Upload(_) if !self.status.is_empty() => self.status.clone(),
+            Job::Upload(_) if self.acked => "sending".to_string(),
+            Job::Upload(_) => "waiting".to_string(),
+        };
+        let done = if self.done { " (done)" } else { "" };
+        format!("[{}] {}: {}{}", self.id, self.label, state, done)
+    }
+}
+
+// sends every request and shows their results as they come, the data of the
+// uploads goes out from other threads while the responses are read here
+fn run_jobs(mut session: Session, jobs: Vec<(String, Request, Job)>) -> io::Result<()> {
+    let mut running = Vec::new();
+    for (label, request, job) in jobs {
+        let id = session.send(request)?;
+        running.push(Running {
+            id,
+            label,
+            job,
+            acked: false,
+            status: String::new(),
+            done: false,
+        });
+    }
+    let writer = Arc::new(std::sync::Mutex::new(session.stream.try_clone()?));
+    let searches = running
+        .iter()
+        .filter(|job| matches!(job.job, Job::Search(_)))
+        .map(|job| job.id)
+        .collect();
+    let _interrupt = cancel_on_interrupt(writer.clone(), searches);
+    let mut senders = Vec::new();
+    let mut last_update_lines = 0;
+    while running.iter().any(|job| !job.done) {
+        let Envelope { id, message } = read_frame(&mut session.stream)?;
+        let Some(job) = running.iter_mut().find(|job| job.id == id) else {
+            return Err(unexpected(id, message));
+        };
+        let redraw = !matches!(message, Response::Found(_));
+        match message {
+            Response::Ack => {
+                job.acked = true;
+                if let Job::Upload(path) = &job.job {
+                    let (writer, path) = (writer.clone(), path.clone());
+                    senders.push(thread::spawn(move || {
+                        send_file(
+                            path,
+                            |chunk| {
+                                let data = Envelope {
+                                    id,
+                                    message: Request::Data(chunk.to_vec()),
+                                };
+                                write_frame(&mut *writer.lock().unwrap(), &data)
+                            },
+                            false,
+                        )
+                    }));
+                }
+            }
+            Response::Indexed { words } => {
+                job.status = format!("{} words indexed", words);
+                job.done = true;
+            }
+            Response::Error { code, message } => {
+                job.status = server_error(code, message).to_string();
+                // a search that started still ends with Done
+                job.done = !job.acked || matches!(job.job, Job::Upload(_));
+            }
+            Response::Done { status, .. } => {
+                if let Job::Search(search_state) = &job.job {
+                    if status != SearchStatus::Complete && job.status.is_empty() {
+                        let summary = search_state.summary();
+                        job.status = format!("{}, {}", summary, status_text(status));
+                    }
+                }
+                job.done = true;
+            }
+            response => match &mut job.job {
+                Job::Search(search_state) => {
+                    if let Some(response) = search_state.record(response) {
+                        return Err(unexpected(id, response));
+                    }
+                }
+                Job::Upload(_) => return Err(unexpected(id, response)),
+            },
+        }
+        if redraw {
+            clear_lines(last_update_lines);
+            for job in &running {
+                println!("{}", job.line());
+            }
+            last_update_lines = running.len() as u32;
+        }
+    }
+    for sender in senders {
+        sender
+            .join()
+            .map_err(|_| io::Error::other("Upload thread failed"))??;
+    }
+
+    // the full results, the searches refused were already shown with their error
+    for job in running.iter_mut().filter(|job| job.acked) {
+        if let Job::Search(search_state) = &mut job.job {
+            println!("{}:", job.label);
+            search_state.last_update_lines = 0;
+            search_state.display();
+        }
     }
-    stream.flush()?;
+    POOL.put(session);
     Ok(())
 }
 
-fn recv_message(stream: &mut TcpStream) -> io::Result<String> {
-    let mut length_bytes = [0u8; 8];
-    stream.read_exact(&mut length_bytes)?;
-    let length = u64::from_be_bytes(length_bytes) as usize;
-    let mut buffer = vec![0; length];
-    stream.read_exact(&mut buffer)?;
-    Ok(String::from_utf8(buffer).unwrap())
+fn status_text(status: SearchStatus) -> &'static str {
+    match status {
+        SearchStatus::Complete => "completed",
+        SearchStatus::Cancelled => "cancelled",
+        SearchStatus::TimedOut => "timed out",
+    }
 }
 
-fn send_file(stream: &mut TcpStream, file: String) -> io::Result<()> {
-    let mut file = match File::open(file) {
-        Ok(file) => file,
-        Err(e) => return Err(e),
-    };
-    let file_size = file.metadata()?.len();
-    stream.write_all(&file_size.to_be_bytes())?;
+// searches running on a connection, with the writer their frames go through
+struct Cancellable {
+    writer: Arc<std::sync::Mutex<TcpStream>>,
+    ids: Vec<RequestId>,
+}
+
+// Ctrl-C cancels the searches until this is dropped
+struct InterruptGuard;
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        CANCELLABLE.lock().unwrap().take();
+    }
+}
+
+fn cancel_on_interrupt(
+    writer: Arc<std::sync::Mutex<TcpStream>>,
+    ids: Vec<RequestId>,
+) -> InterruptGuard {
+    *CANCELLABLE.lock().unwrap() = Some(Cancellable { writer, ids });
+    InterruptGuard
+}
+
+// Ctrl-C during a search asks the server to stop it, the results found so
+// far still come with Done, outside of a search it quits as it used to
+async fn handle_interrupts() {
+    while tokio::signal::ctrl_c().await.is_ok() {
+        let cancellable = CANCELLABLE.lock().unwrap();
+        let Some(cancellable) = cancellable.as_ref() else {
+            println!();
+            std::process::exit(130);
+        };
+        let mut writer = cancellable.writer.lock().unwrap();
+        for &id in &cancellable.ids {
+            let cancel = Envelope {
+                id,
+                message: Request::Cancel,
+            };
+            if let Err(e) = write_frame(&mut *writer, &cancel) {
+                println!("Failed to cancel search {}: {}", id, e);
+            }
+        }
+    }
+}
+
+fn unexpected(id: RequestId, response: Response) -> io::Error {
+    match response {
+        Response::Error { code, message } if id == CONNECTION_ID => server_error(code, message),
+        response => io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unexpected response to request {}: {:?}", id, response),
+        ),
+    }
+}
+
+fn clear_lines(lines: u32) {
+    for _ in 0..lines {
+        execute!(stdout(), crossterm::cursor::MoveUp(1)).unwrap();
+        execute!(stdout(), crossterm::cursor::MoveToColumn(0)).unwrap();
+        execute!(stdout(), Clear(ClearType::CurrentLine)).unwrap();
+    }
+}
+
+// rebuilds the query typed by the user, putting back the quotes of phrases
+fn join_query(args: &[String]) -> String {
+    args.iter()
+        .map(|arg| {
+            if arg.contains(char::is_whitespace) {
+                format!("\"{}\"", arg)
+            } else {
+                arg.clone()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// a number given to a search option, e.g. --context=40
+fn parse_number(option: &str, value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid number in {}", option))
+}
+
+// reads the file in chunks for `send_chunk`, showing the progress if asked
+fn send_file(
+    file: String,
+    mut send_chunk: impl FnMut(&[u8]) -> io::Result<()>,
+    show_progress: bool,
+) -> io::Result<()> {
+    let mut file = File::open(file)?;
 
     let mut buffer = [0u8; BUFFER_SIZE];
     let file_size = file.metadata()?.len();
@@ -373,63 +855,186 @@ fn send_file(stream: &mut TcpStream, file: String) -> io::Result<()> {
         if n == 0 {
             break; // End of file
         }
-        send_chunk(stream, &buffer[..n])?;
+        send_chunk(&buffer[..n])?;
         byte_count += n as u64;
+        if !show_progress {
+            continue;
+        }
         let progress = (byte_count as f64 / file_size as f64) * 100.0;
         println!("Uploading: {:.2}%", progress);
         execute!(stdout(), crossterm::cursor::MoveUp(1)).unwrap();
         execute!(stdout(), crossterm::cursor::MoveToColumn(0)).unwrap();
         execute!(stdout(), Clear(ClearType::CurrentLine)).unwrap();
     }
-    wait_for_ack(stream)?;
     Ok(())
 }
 
-fn send_chunk(stream: &mut TcpStream, chunk: &[u8]) -> io::Result<()> {
-    stream.write_all(&chunk)?;
-    Ok(())
+// an error frame, shown as "No such file: a.txt (not found)"
+fn server_error(code: ErrorCode, message: String) -> io::Error {
+    let kind = match code {
+        ErrorCode::NotFound => io::ErrorKind::NotFound,
+        ErrorCode::InvalidName | ErrorCode::BadRequest => io::ErrorKind::InvalidInput,
+        ErrorCode::DiskFull => io::ErrorKind::StorageFull,
+        ErrorCode::Busy => io::ErrorKind::ResourceBusy,
+        ErrorCode::Database | ErrorCode::Internal => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, format!("{} ({})", message, code))
 }
 
-fn wait_for_ack(stream: &mut TcpStream) -> io::Result<()> {
-    let mut ack = [0u8; 2];
-    match stream.read_exact(&mut ack) {
-        Ok(_) => {
-            if ack == ACK {
-                return Ok(());
-            } else {
-                return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid ack"));
-            }
+fn busy_error(retry_after_ms: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ResourceBusy,
+        format!("Server busy, retry after {} ms", retry_after_ms),
+    )
+}
+
+// a connection past the handshake, it can take any number of requests
+struct Session {
+    stream: TcpStream,
+    welcome: Welcome,
+    // for the next request
+    next_id: RequestId,
+}
+
+impl Session {
+    // connects and does the handshake, the server may refuse or be busy
+    fn connect(server_addr: &str) -> io::Result<Session> {
+        let mut stream = TcpStream::connect(server_addr)?;
+        write_frame(
+            &mut stream,
+            &Request::Hello(Hello::new(BUILD, CAPABILITIES)),
+        )?;
+        match read_frame(&mut stream)? {
+            Response::Welcome(welcome) => Ok(Session {
+                stream,
+                welcome,
+                next_id: CONNECTION_ID + 1,
+            }),
+            Response::Busy { retry_after_ms } => Err(busy_error(retry_after_ms)),
+            Response::Error { code, message } => Err(server_error(code, message)),
+            response => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unexpected handshake response: {:?}", response),
+            )),
+        }
+    }
+
+    // sends a new request, the id tells its responses apart
+    fn send(&mut self, request: Request) -> io::Result<RequestId> {
+        let id = self.next_id;
+        self.next_id = self.next_id.checked_add(1).unwrap_or(CONNECTION_ID + 1);
+        self.send_as(id, request)?;
+        Ok(id)
+    }
+
+    // sends a frame of a request already sent, like the data of an upload
+    fn send_as(&mut self, id: RequestId, message: Request) -> io::Result<()> {
+        write_frame(&mut self.stream, &Envelope { id, message })
+    }
+
+    // the next response, for a command with a single request running
+    fn recv(&mut self, id: RequestId) -> io::Result<Response> {
+        let envelope: Envelope<Response> = read_frame(&mut self.stream)?;
+        if envelope.id != id {
+            return Err(unexpected(envelope.id, envelope.message));
         }
-        Err(e) => {
-            return Err(e);
+        Ok(envelope.message)
+    }
+
+    // the server closes idle connections, which shows as the end of the stream,
+    // and nothing else should be waiting to be read between requests
+    fn is_closed(&self) -> bool {
+        if self.stream.set_nonblocking(true).is_err() {
+            return true;
+        }
+        let peeked = self.stream.peek(&mut [0u8; 1]);
+        let open = matches!(peeked, Err(e) if e.kind() == io::ErrorKind::WouldBlock);
+        !open || self.stream.set_nonblocking(false).is_err()
+    }
+}
+
+// sessions waiting for the next request
+struct Pool {
+    server_addr: String,
+    idle: std::sync::Mutex<Vec<Session>>,
+}
+
+impl Pool {
+    fn new(server_addr: &str) -> Self {
+        Pool {
+            server_addr: server_addr.to_string(),
+            idle: std::sync::Mutex::new(Vec::new()),
+        }
+    }
+
+    // an idle session that is still open, or a new one
+    fn take(&self) -> io::Result<Session> {
+        loop {
+            let next = self.idle.lock().unwrap().pop();
+            match next {
+                Some(session) if !session.is_closed() => return Ok(session),
+                Some(_) => continue,
+                None => return Session::connect(&self.server_addr),
+            }
         }
     }
+
+    // keeps a session whose requests are over
+    fn put(&self, session: Session) {
+        self.idle.lock().unwrap().push(session);
+    }
+}
+
+fn session() -> Result<Session, String> {
+    POOL.take()
+        .map_err(|e| format!("Error connecting to {}: {}", *SERVER_ADDR, e))
+}
+
+fn wait_for_ack(session: &mut Session, id: RequestId) -> io::Result<()> {
+    match session.recv(id)? {
+        Response::Ack => Ok(()),
+        Response::Error { code, message } => Err(server_error(code, message)),
+        response => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unexpected response: {:?}", response),
+        )),
+    }
 }
 
 async fn test(n_requests: usize, full_duration: u64, search_term: String) {
-    let server_addr = Arc::new(SERVER_ADDR.to_string());
+    // requests that don't overlap share connections, closed when the test ends
+    let pool = Arc::new(Pool::new(&SERVER_ADDR));
     let search_term = Arc::new(search_term);
     let interval = Duration::from_secs(full_duration) / n_requests as u32;
 
     // Shared state across tasks for accumulating time
     let time_acc = Arc::new(Mutex::new(Duration::from_secs(0)));
+    let completed = Arc::new(Mutex::new(0usize));
+    let rejected = Arc::new(Mutex::new(0usize));
 
     let mut handles = Vec::new();
 
     for index in 0..n_requests {
-        let server_addr = server_addr.clone();
+        let pool = pool.clone();
         let search_term = search_term.clone();
         let time_acc = time_acc.clone();
+        let completed = completed.clone();
+        let rejected = rejected.clone();
 
         // Sleep before spawning the request task.
         tokio::time::sleep(interval).await;
         println!("sent n{index} request");
         let handle = tokio::spawn(async move {
-            match send_search_request(&server_addr, &search_term, index as u32).await {
+            match send_search_request(&pool, &search_term, index as u32).await {
                 Ok(time) => {
                     println!("Request {} completed in {:.2?}", index, time);
                     let mut time_acc_lock = time_acc.lock().await;
-                    *time_acc_lock = *time_acc_lock + time;
+                    *time_acc_lock += time;
+                    *completed.lock().await += 1;
+                }
+                Err(e) if e.kind() == io::ErrorKind::ResourceBusy => {
+                    println!("Request {} rejected: {}", index, e);
+                    *rejected.lock().await += 1;
                 }
                 Err(e) => {
                     eprintln!("Failed to send request: {:?}", e);
@@ -447,46 +1052,57 @@ async fn test(n_requests: usize, full_duration: u64, search_term: String) {
     }
 
     let final_time_acc = time_acc.lock().await;
+    let completed = *completed.lock().await;
     println!(
-        "Sent {} requests, {:.2?} seconds average per request.",
+        "Sent {} requests, {} completed, {} rejected by a busy server, {:.2?} seconds average per request.",
         n_requests,
-        final_time_acc.as_secs_f64() / n_requests as f64
+        completed,
+        *rejected.lock().await,
+        final_time_acc.as_secs_f64() / completed.max(1) as f64
     );
 }
 
 async fn send_search_request(
-    server_addr: &str,
+    pool: &Pool,
     search_term: &str,
     n_request: u32,
 ) -> tokio::io::Result<Duration> {
-    let mut stream = TcpStream::connect(server_addr)?;
-    let mut time = Instant::now();
-    send_command(&mut stream, SEARCH_CMD)?;
-    send_message(&mut stream, search_term)?;
-    wait_for_ack(&mut stream)?;
+    let time = Instant::now();
+    let mut session = pool.take()?;
+    let request = Request::Search {
+        query: search_term.to_string(),
+        options: SearchOptions::default(),
+    };
+    let id = session.send(request)?;
+    wait_for_ack(&mut session, id)?;
 
     loop {
-        match recv_message(&mut stream) {
-            Ok(message) => {
-                if !message.starts_with("done:")
-                    && !message.starts_with("update:")
-                    && !message.starts_with("found:")
-                    && !message.starts_with("searching:")
-                    && message != ""
-                {
-                    println!("{n_request} received strange message{}", message);
-                } else if (message.starts_with("done:")) {
-                    return Ok(time.elapsed());
-                }
+        match session.recv(id) {
+            Ok(Response::Done { .. }) => {
+                pool.put(session);
+                return Ok(time.elapsed());
             }
+            Ok(Response::Searching { .. } | Response::Progress { .. } | Response::Found(_)) => (),
+            Ok(response) => println!("{n_request} received strange message {:?}", response),
             Err(e) => {
-                return Err(tokio::io::Error::new(tokio::io::ErrorKind::Other, e));
+                return Err(tokio::io::Error::other(e));
             }
         }
     }
 }
+#[derive(Parser)]
+#[command(about = "MyGoogle Search client")]
+struct Args {
+    /// Address of the server
+    #[arg(long, env = "MYGOOGLE_SERVER", default_value = "127.0.0.1:5000")]
+    server: String,
+}
+
 #[tokio::main(worker_threads = 1024)]
 async fn main() {
+    // reads the flags before the first prompt, to fail early on bad ones
+    LazyLock::force(&SERVER_ADDR);
+    tokio::spawn(handle_interrupts());
     loop {
         print!("Enter command: ");
         io::stdout().flush().unwrap(); // Ensure prompt is displayed before input
@@ -495,11 +1111,7 @@ async fn main() {
         io::stdin().read_line(&mut input).unwrap();
 
         // split by spaces
-        let mut args: Vec<String> = input
-            .trim()
-            .split_whitespace()
-            .map(|s| s.to_string())
-            .collect();
+        let mut args: Vec<String> = input.split_whitespace().map(|s| s.to_string()).collect();
 
         // handle quote args, if starts with quote, join until end quote
         let mut i = 0;
@@ -510,8 +1122,8 @@ async fn main() {
                     j += 1;
                 }
                 let mut arg = args[i].clone();
-                for k in i + 1..=j {
-                    arg = arg + " " + &args[k];
+                for next_arg in &args[i + 1..=j] {
+                    arg = arg + " " + next_arg;
                 }
                 arg = arg.replace("\"", "");
                 args[i] = arg;
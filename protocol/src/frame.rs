@@ -0,0 +1,228 @@
+// A frame is the length of the rest of the frame (u32, big endian), the
+// frame version (u8) and the bincode encoded message.
+
+use std::io::{self, Read, Write};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+// layout of the frame itself, the messages are versioned by the handshake
+pub const FRAME_VERSION: u8 = 1;
+// larger frames are refused before reading them
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+const LENGTH_SIZE: usize = 4;
+
+fn encode<T: Serialize>(message: &T) -> io::Result<Vec<u8>> {
+    let payload = bincode::serialize(message).map_err(io::Error::other)?;
+    let length = payload.len() + 1;
+    if length > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Frame of {} bytes is over the limit", length),
+        ));
+    }
+    let mut frame = Vec::with_capacity(LENGTH_SIZE + length);
+    frame.extend_from_slice(&(length as u32).to_be_bytes());
+    frame.push(FRAME_VERSION);
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+fn check_length(length: usize) -> io::Result<()> {
+    if length == 0 || length > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid frame length: {}", length),
+        ));
+    }
+    Ok(())
+}
+
+// `body` is the frame without the length
+fn decode<T: DeserializeOwned>(body: &[u8]) -> io::Result<T> {
+    let version = body[0];
+    if version != FRAME_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Unsupported frame version {}, expected {}",
+                version, FRAME_VERSION
+            ),
+        ));
+    }
+    bincode::deserialize(&body[1..]).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, message: &T) -> io::Result<()> {
+    writer.write_all(&encode(message)?)?;
+    writer.flush()
+}
+
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> io::Result<T> {
+    let mut length_bytes = [0u8; LENGTH_SIZE];
+    reader.read_exact(&mut length_bytes)?;
+    let length = u32::from_be_bytes(length_bytes) as usize;
+    check_length(length)?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    decode(&body)
+}
+
+// frames for tokio_util::codec::Framed, decoding `In` and encoding `Out`
+#[cfg(feature = "tokio")]
+pub struct FrameCodec<In, Out> {
+    _messages: std::marker::PhantomData<fn(Out) -> In>,
+}
+
+#[cfg(feature = "tokio")]
+impl<In, Out> FrameCodec<In, Out> {
+    pub fn new() -> Self {
+        FrameCodec {
+            _messages: std::marker::PhantomData,
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<In, Out> Default for FrameCodec<In, Out> {
+    fn default() -> Self {
+        FrameCodec::new()
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<In: DeserializeOwned, Out> tokio_util::codec::Decoder for FrameCodec<In, Out> {
+    type Item = In;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut bytes::BytesMut) -> io::Result<Option<In>> {
+        use bytes::Buf;
+
+        if src.len() < LENGTH_SIZE {
+            return Ok(None);
+        }
+        let mut length_bytes = [0u8; LENGTH_SIZE];
+        length_bytes.copy_from_slice(&src[..LENGTH_SIZE]);
+        let length = u32::from_be_bytes(length_bytes) as usize;
+        check_length(length)?;
+        if src.len() < LENGTH_SIZE + length {
+            src.reserve(LENGTH_SIZE + length - src.len());
+            return Ok(None);
+        }
+        src.advance(LENGTH_SIZE);
+        let body = src.split_to(length);
+        decode(&body).map(Some)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<In, Out: Serialize> tokio_util::codec::Encoder<Out> for FrameCodec<In, Out> {
+    type Error = io::Error;
+
+    fn encode(&mut self, message: Out, dst: &mut bytes::BytesMut) -> io::Result<()> {
+        dst.extend_from_slice(&encode(&message)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Envelope, Request, Response, SearchOptions};
+
+    fn search() -> Envelope<Request> {
+        Envelope {
+            id: 7,
+            message: Request::Search {
+                query: "\"tcp socket\" NEAR/3 conexão".to_string(),
+                options: SearchOptions::default(),
+            },
+        }
+    }
+
+    #[test]
+    fn round_trips_messages() {
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, &search()).unwrap();
+        write_frame(&mut bytes, &Response::Ack).unwrap();
+        assert_eq!(bytes[LENGTH_SIZE], FRAME_VERSION);
+
+        let mut reader = bytes.as_slice();
+        assert_eq!(
+            read_frame::<_, Envelope<Request>>(&mut reader).unwrap(),
+            search()
+        );
+        assert_eq!(
+            read_frame::<_, Response>(&mut reader).unwrap(),
+            Response::Ack
+        );
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_frames() {
+        let mut frame = encode(&Response::Ack).unwrap();
+        frame[LENGTH_SIZE] = FRAME_VERSION + 1;
+        let error = read_frame::<_, Response>(&mut frame.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        for length in [0, MAX_FRAME_SIZE as u32 + 1] {
+            let frame = length.to_be_bytes();
+            let error = read_frame::<_, Response>(&mut frame.as_slice()).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+
+        let too_large = Request::Data(vec![0; MAX_FRAME_SIZE]);
+        let error = write_frame(&mut Vec::new(), &too_large).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn reports_a_cut_frame() {
+        let frame = encode(&search()).unwrap();
+        let cut = &frame[..frame.len() - 1];
+        let error = read_frame::<_, Envelope<Request>>(&mut &cut[..]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn decodes_frames_arriving_in_pieces() {
+        use tokio_util::codec::{Decoder, Encoder};
+
+        let mut codec: FrameCodec<Envelope<Request>, Envelope<Request>> = FrameCodec::new();
+        let mut encoded = bytes::BytesMut::new();
+        codec.encode(search(), &mut encoded).unwrap();
+        codec.encode(search(), &mut encoded).unwrap();
+
+        // a byte at a time, nothing comes out until a whole frame is there
+        let mut src = bytes::BytesMut::new();
+        let mut decoded = Vec::new();
+        for byte in encoded.iter() {
+            src.extend_from_slice(&[*byte]);
+            if let Some(message) = codec.decode(&mut src).unwrap() {
+                decoded.push(message);
+                assert!(src.is_empty());
+            }
+        }
+        assert_eq!(decoded, [search(), search()]);
+
+        // two frames and the start of a third in one read
+        let mut src = encoded.clone();
+        src.extend_from_slice(&encoded[..3]);
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(search()));
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(search()));
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        assert_eq!(src.len(), 3);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn rejects_a_bad_length_before_its_body() {
+        use tokio_util::codec::Decoder;
+
+        let mut codec: FrameCodec<Response, Response> = FrameCodec::new();
+        let mut src = bytes::BytesMut::from(&(MAX_FRAME_SIZE as u32 + 1).to_be_bytes()[..]);
+        let error = codec.decode(&mut src).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
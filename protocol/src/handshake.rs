@@ -0,0 +1,198 @@
+// The first frames of every connection: the client says which protocol
+// versions and features it knows, the server picks a version or refuses.
+// Hello, Welcome and Error keep their place and fields in every version so
+// any two peers can at least tell each other they are incompatible. These
+// frames are sent as they are, the ones after them in an Envelope.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+// version of the requests and responses, bumped whenever one of them changes
+pub const PROTOCOL_VERSION: u32 = 8;
+// oldest version this build still speaks. There is a single layout of the
+// messages, so it moves with PROTOCOL_VERSION and every bump makes older
+// peers get refused in the handshake. Lowering it needs decoding the older
+// layouts first.
+pub const MIN_PROTOCOL_VERSION: u32 = PROTOCOL_VERSION;
+
+// optional features, a request may only use the ones both peers have
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    pub const REGEX: Capabilities = Capabilities(1 << 0);
+    pub const FUZZY: Capabilities = Capabilities(1 << 1);
+    pub const EXACT_ACCENTS: Capabilities = Capabilities(1 << 2);
+
+    const NAMES: [(Capabilities, &'static str); 3] = [
+        (Capabilities::REGEX, "regex"),
+        (Capabilities::FUZZY, "fuzzy"),
+        (Capabilities::EXACT_ACCENTS, "exact-accents"),
+    ];
+
+    pub const fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn union(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+
+    pub const fn intersection(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+impl fmt::Display for Capabilities {
+    // "regex, fuzzy", unknown bits from newer peers are left out
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<&str> = Capabilities::NAMES
+            .iter()
+            .filter(|(capability, _)| self.contains(*capability))
+            .map(|(_, name)| *name)
+            .collect();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(", "))
+        }
+    }
+}
+
+// sent by the client before its request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Hello {
+    pub min_version: u32,
+    pub max_version: u32,
+    // name and version of the client build
+    pub build: String,
+    pub capabilities: Capabilities,
+}
+
+// the server answer to a compatible Hello
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Welcome {
+    // the version used for the rest of the connection
+    pub version: u32,
+    // name and version of the server build
+    pub build: String,
+    // the capabilities both peers have
+    pub capabilities: Capabilities,
+}
+
+impl Hello {
+    pub fn new(build: &str, capabilities: Capabilities) -> Self {
+        Hello {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            build: build.to_string(),
+            capabilities,
+        }
+    }
+
+    // the answer of a server with `capabilities`, Err with the reason to refuse
+    pub fn negotiate(&self, build: &str, capabilities: Capabilities) -> Result<Welcome, String> {
+        let version = self.max_version.min(PROTOCOL_VERSION);
+        if version < self.min_version.max(MIN_PROTOCOL_VERSION) {
+            return Err(format!(
+                "Incompatible protocol: client {} speaks versions {}-{}, server {} speaks {}-{}",
+                self.build,
+                self.min_version,
+                self.max_version,
+                build,
+                MIN_PROTOCOL_VERSION,
+                PROTOCOL_VERSION
+            ));
+        }
+        Ok(Welcome {
+            version,
+            build: build.to_string(),
+            capabilities: self.capabilities.intersection(capabilities),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(min_version: u32, max_version: u32, capabilities: Capabilities) -> Hello {
+        Hello {
+            min_version,
+            max_version,
+            build: "client 1.0".to_string(),
+            capabilities,
+        }
+    }
+
+    #[test]
+    fn agrees_on_the_same_version() {
+        let welcome = Hello::new("client 1.0", Capabilities::NONE)
+            .negotiate("server 1.0", Capabilities::NONE)
+            .unwrap();
+        assert_eq!(welcome.version, PROTOCOL_VERSION);
+        assert_eq!(welcome.build, "server 1.0");
+    }
+
+    #[test]
+    fn picks_the_newest_version_both_speak() {
+        let newer = hello(
+            MIN_PROTOCOL_VERSION,
+            PROTOCOL_VERSION + 3,
+            Capabilities::NONE,
+        );
+        let welcome = newer.negotiate("server 1.0", Capabilities::NONE).unwrap();
+        assert_eq!(welcome.version, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn refuses_versions_it_does_not_speak() {
+        let older = hello(1, MIN_PROTOCOL_VERSION - 1, Capabilities::NONE);
+        let message = older
+            .negotiate("server 1.0", Capabilities::NONE)
+            .unwrap_err();
+        assert_eq!(
+            message,
+            format!(
+                "Incompatible protocol: client client 1.0 speaks versions 1-{}, server server 1.0 speaks {}-{}",
+                MIN_PROTOCOL_VERSION - 1,
+                MIN_PROTOCOL_VERSION,
+                PROTOCOL_VERSION
+            )
+        );
+        let newer = hello(
+            PROTOCOL_VERSION + 1,
+            PROTOCOL_VERSION + 2,
+            Capabilities::NONE,
+        );
+        assert!(newer.negotiate("server 1.0", Capabilities::NONE).is_err());
+    }
+
+    #[test]
+    fn keeps_the_capabilities_both_have() {
+        let client = hello(
+            MIN_PROTOCOL_VERSION,
+            PROTOCOL_VERSION,
+            Capabilities::REGEX.union(Capabilities::FUZZY),
+        );
+        let server = Capabilities::FUZZY.union(Capabilities::EXACT_ACCENTS);
+        let welcome = client.negotiate("server 1.0", server).unwrap();
+        assert_eq!(welcome.capabilities, Capabilities::FUZZY);
+        assert!(!welcome.capabilities.contains(Capabilities::REGEX));
+    }
+
+    #[test]
+    fn names_the_known_capabilities() {
+        assert_eq!(Capabilities::NONE.to_string(), "none");
+        assert_eq!(
+            Capabilities::REGEX
+                .union(Capabilities::EXACT_ACCENTS)
+                .to_string(),
+            "regex, exact-accents"
+        );
+        // bits of newer peers
+        assert_eq!(Capabilities(1 << 3 | 1 << 1).to_string(), "fuzzy");
+    }
+}
@@ -0,0 +1,17 @@
+// messages exchanged by the client and the server, and how they are framed on
+// the socket. A connection starts with the handshake and then carries any
+// number of requests, which may overlap: every frame after the handshake has
+// the id of its request and the responses to different requests interleave.
+
+mod frame;
+mod handshake;
+mod message;
+
+#[cfg(feature = "tokio")]
+pub use frame::FrameCodec;
+pub use frame::{read_frame, write_frame, FRAME_VERSION, MAX_FRAME_SIZE};
+pub use handshake::{Capabilities, Hello, Welcome, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+pub use message::{
+    Envelope, ErrorCode, Found, Fuzziness, Request, RequestId, Response, SearchMode, SearchOptions,
+    SearchOutput, SearchStatus, CONNECTION_ID, DEFAULT_CONTEXT,
+};
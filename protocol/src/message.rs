@@ -0,0 +1,253 @@
+use std::{fmt, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::handshake::{Capabilities, Hello, Welcome};
+
+// bytes of context around a match when the request doesn't say
+pub const DEFAULT_CONTEXT: usize = 20;
+
+// ties the responses to their request, chosen by the client for each request
+pub type RequestId = u32;
+// responses about the connection rather than one request, requests start at 1
+pub const CONNECTION_ID: RequestId = 0;
+
+// every frame after the handshake, so requests can overlap on a connection
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub id: RequestId,
+    pub message: T,
+}
+
+// the variants before the first request keep their place in every version
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Request {
+    // the first frame of every connection, answered with Welcome or Error
+    Hello(Hello),
+    // answered with Ack, then the client sends `size` bytes in Data frames
+    // with the same id and the server answers with Indexed or Error
+    Upload {
+        name: String,
+        size: u64,
+    },
+    // a piece of the file being uploaded
+    Data(Vec<u8>),
+    // answered with Ack, the results and Done
+    Search {
+        query: String,
+        options: SearchOptions,
+    },
+    // answered with Ack once the file is gone
+    Delete {
+        name: String,
+    },
+    // answered with Files
+    List,
+    // sent with the id of a running search, which ends early with a Done
+    // Cancelled, other requests finish as usual
+    Cancel,
+}
+
+// the variants up to Busy keep their place in every version
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Response {
+    Welcome(Welcome),
+    // the request failed, `message` is meant for people and `code` for programs
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+    // the server can't take the connection now, sent instead of Welcome
+    Busy {
+        retry_after_ms: u64,
+    },
+    Ack,
+    // the uploaded file was stored and this many words indexed
+    Indexed {
+        words: u64,
+    },
+    Files {
+        names: Vec<String>,
+    },
+    // a file is about to be searched
+    Searching {
+        path: String,
+        size: u64,
+    },
+    // bytes of the file searched so far
+    Progress {
+        path: String,
+        bytes_read: u64,
+    },
+    Found(Found),
+    // the search is over, or stopped early with the results found so far
+    Done {
+        elapsed: Duration,
+        status: SearchStatus,
+    },
+    // a file with matches, instead of its Found frames when the search only
+    // asked for counts or files; `count` is None when only files were asked
+    Matched {
+        path: String,
+        score: f64,
+        count: Option<u64>,
+    },
+}
+
+// why a request failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    // the file isn't stored on the server
+    NotFound,
+    // the file name can't be used to store a file
+    InvalidName,
+    // no space left to store the upload
+    DiskFull,
+    // the index couldn't be read or updated
+    Database,
+    // the server can't do it now, the same request may work later
+    Busy,
+    // the request is malformed, not allowed or not expected now
+    BadRequest,
+    // anything else that went wrong on the server
+    Internal,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ErrorCode::NotFound => "not found",
+            ErrorCode::InvalidName => "invalid name",
+            ErrorCode::DiskFull => "disk full",
+            ErrorCode::Database => "database error",
+            ErrorCode::Busy => "busy",
+            ErrorCode::BadRequest => "bad request",
+            ErrorCode::Internal => "internal error",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// an occurrence of the query in a file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Found {
+    pub path: String,
+    // absolute byte offset of the match
+    pub byte: u64,
+    pub line: u64,
+    pub column: u64,
+    // BM25 relevance of the file, 0 for scanned files
+    pub score: f64,
+    // first and last word of the match, None for scanned files
+    pub positions: Option<(u64, u64)>,
+    // the text or indexed words that matched
+    pub matched: String,
+    // the match with the requested context around it, or every line it is
+    // on when the search asked for context lines
+    pub snippet: String,
+    // the lines before and after those, empty without context lines
+    pub lines_before: Vec<String>,
+    pub lines_after: Vec<String>,
+}
+
+// how a search ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SearchStatus {
+    Complete,
+    // by a Cancel frame, or because the client went away
+    Cancelled,
+    // the deadline of the search passed
+    TimedOut,
+}
+
+// what is sent for the files with matches
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SearchOutput {
+    // a Found frame for every occurrence
+    Occurrences,
+    // a Matched frame with the number of occurrences
+    Count,
+    // a Matched frame, a scan stops at the first occurrence of each file
+    FilesWithMatches,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SearchMode {
+    // evaluate the query language against the inverted index
+    Index,
+    // scan the stored files for the text as typed, accents included
+    Literal,
+    // scan the stored files for a regular expression, accents included
+    Regex,
+}
+
+// typo tolerance of the index terms
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Fuzziness {
+    Off,
+    // edit distance picked from the term length
+    Auto,
+    Max(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SearchOptions {
+    pub mode: SearchMode,
+    pub output: SearchOutput,
+    pub fuzzy: Fuzziness,
+    // "acao" only finds "acao", not "ação", index searches only as scans
+    // always keep the accents
+    pub exact_accents: bool,
+    // "Rust" doesn't match "rust"
+    pub case_sensitive: bool,
+    // "art" doesn't match inside "start", and in the index not "arts" either
+    pub whole_word: bool,
+    // bytes of context sent before and after each match
+    pub before: usize,
+    pub after: usize,
+    // send the whole lines of each match and this many lines around them
+    // instead of the bytes of context, like grep -C
+    pub context_lines: Option<usize>,
+    // the search stops with TimedOut this long after the server got it
+    pub deadline: Option<Duration>,
+    // only the best files by score, the first ones found when scanning
+    pub limit: Option<usize>,
+    // occurrences sent for each file, the first ones in the file
+    pub max_occurrences_per_file: Option<usize>,
+}
+
+impl SearchOptions {
+    // the capabilities both peers need for a search with these options
+    pub fn capabilities(&self) -> Capabilities {
+        let mut capabilities = Capabilities::NONE;
+        if self.mode == SearchMode::Regex {
+            capabilities = capabilities.union(Capabilities::REGEX);
+        }
+        if self.fuzzy != Fuzziness::Off {
+            capabilities = capabilities.union(Capabilities::FUZZY);
+        }
+        if self.exact_accents {
+            capabilities = capabilities.union(Capabilities::EXACT_ACCENTS);
+        }
+        capabilities
+    }
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        SearchOptions {
+            mode: SearchMode::Index,
+            output: SearchOutput::Occurrences,
+            fuzzy: Fuzziness::Off,
+            exact_accents: false,
+            case_sensitive: false,
+            whole_word: false,
+            before: DEFAULT_CONTEXT,
+            after: DEFAULT_CONTEXT,
+            context_lines: None,
+            deadline: None,
+            limit: None,
+            max_occurrences_per_file: None,
+        }
+    }
+}
@@ -0,0 +1,249 @@
+// turns the words read from a text into the terms stored in the inverted index,
+// the same analyzer must be used for the files and for the queries
+
+use std::collections::HashSet;
+
+use clap::ValueEnum;
+use rust_stemmers::{Algorithm, Stemmer};
+use serde::{Deserialize, Serialize};
+
+use crate::index::fold;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    Portuguese,
+    English,
+}
+
+impl Language {
+    // as stored with the index, whose terms depend on it
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Language::Portuguese => "portuguese",
+            Language::English => "english",
+        }
+    }
+}
+
+// a step of the analyzer, None drops the word
+pub trait Filter: Send + Sync {
+    fn apply(&self, word: String) -> Option<String>;
+}
+
+// a lower case word goes through each filter in order
+pub struct Analyzer {
+    filters: Vec<Box<dyn Filter>>,
+}
+
+impl Analyzer {
+    pub fn new(filters: Vec<Box<dyn Filter>>) -> Self {
+        Analyzer { filters }
+    }
+
+    // Stemming for the language of the corpus, without the common Portuguese
+    // and English words. A single stemmer runs: each one cuts words of the
+    // other language into unrelated stems ("time" -> "tim", "casa" -> "cas").
+    // Portuguese -ão plurals are made singular while they still have their
+    // accents, and stemming comes after folding so a word typed without its
+    // accents gets the same term ("conexoes" and "conexões").
+    pub fn standard(language: Language) -> Self {
+        let mut filters: Vec<Box<dyn Filter>> = vec![Box::new(Stopwords::new(&[
+            Language::Portuguese,
+            Language::English,
+        ]))];
+        if language == Language::Portuguese {
+            filters.push(Box::new(Plurals));
+        }
+        filters.push(Box::new(Fold));
+        filters.push(Box::new(Stem::new(language)));
+        Analyzer::new(filters)
+    }
+
+    // the indexed term for the word, None if it is not indexed
+    pub fn analyze(&self, word: &str) -> Option<String> {
+        self.filters
+            .iter()
+            .try_fold(word.to_lowercase(), |word, filter| filter.apply(word))
+    }
+}
+
+// drops words too common to tell files apart, "de", "the", ...
+pub struct Stopwords {
+    // without accents
+    words: HashSet<&'static str>,
+}
+
+impl Stopwords {
+    pub fn new(languages: &[Language]) -> Self {
+        let words = languages
+            .iter()
+            .flat_map(|language| match language {
+                Language::Portuguese => PORTUGUESE_STOPWORDS,
+                Language::English => ENGLISH_STOPWORDS,
+            })
+            .copied()
+            .collect();
+        Stopwords { words }
+    }
+}
+
+impl Filter for Stopwords {
+    fn apply(&self, word: String) -> Option<String> {
+        if self.words.contains(fold(&word).as_str()) {
+            None
+        } else {
+            Some(word)
+        }
+    }
+}
+
+// reduces the word to its stem, "conexoes" and "conexao" -> "conexa"
+pub struct Stem {
+    stemmer: Stemmer,
+}
+
+impl Stem {
+    pub fn new(language: Language) -> Self {
+        let algorithm = match language {
+            Language::Portuguese => Algorithm::Portuguese,
+            Language::English => Algorithm::English,
+        };
+        Stem {
+            stemmer: Stemmer::create(algorithm),
+        }
+    }
+}
+
+impl Filter for Stem {
+    fn apply(&self, word: String) -> Option<String> {
+        Some(self.stemmer.stem(&word).into_owned())
+    }
+}
+
+// the Portuguese stemmer keeps the plurals of -ão words apart from their
+// singular, "conexões" -> "conexão"; runs before the accents are folded
+pub struct Plurals;
+
+impl Filter for Plurals {
+    fn apply(&self, word: String) -> Option<String> {
+        if let Some((_, singular)) = PLURAL_WORDS.iter().find(|(plural, _)| *plural == word) {
+            return Some(singular.to_string());
+        }
+        let singular = PLURALS.iter().find_map(|(plural, singular)| {
+            let stem = word.strip_suffix(plural)?;
+            Some(format!("{}{}", stem, singular))
+        });
+        Some(singular.unwrap_or(word))
+    }
+}
+
+// plurals the suffixes would get wrong
+const PLURAL_WORDS: [(&str, &str); 1] = [("mães", "mãe")];
+
+// plurals of -ão words and their singular. Only -ções, -sões, -xões and -zões
+// are recognized without their accents, other words ending in -oes, -aes or
+// -aos are rarely -ão plurals ("caos", "shoes", "heroes").
+const PLURALS: [(&str, &str); 7] = [
+    ("ões", "ão"),
+    ("ães", "ão"),
+    ("ãos", "ão"),
+    ("coes", "cao"),
+    ("soes", "sao"),
+    ("xoes", "xao"),
+    ("zoes", "zao"),
+];
+
+// removes the accents, "ação" -> "acao"
+pub struct Fold;
+
+impl Filter for Fold {
+    fn apply(&self, word: String) -> Option<String> {
+        Some(fold(&word))
+    }
+}
+
+// without accents, like the words they are compared to
+const PORTUGUESE_STOPWORDS: &[&str] = &[
+    "a", "ao", "aos", "aquela", "aquelas", "aquele", "aqueles", "aquilo", "as", "ate", "com",
+    "como", "da", "das", "de", "dela", "delas", "dele", "deles", "depois", "do", "dos", "e", "ela",
+    "elas", "ele", "eles", "em", "entre", "era", "essa", "essas", "esse", "esses", "esta", "estas",
+    "este", "estes", "eu", "foi", "ha", "isso", "isto", "ja", "lhe", "lhes", "mais", "mas", "me",
+    "mesmo", "meu", "minha", "muito", "na", "nas", "nao", "nem", "no", "nos", "nossa", "nosso",
+    "num", "numa", "o", "os", "ou", "para", "pela", "pelas", "pelo", "pelos", "por", "qual",
+    "quando", "que", "quem", "se", "sem", "ser", "seu", "seus", "so", "sua", "suas", "tambem",
+    "te", "tem", "teu", "tu", "tua", "um", "uma", "umas", "uns", "voce", "voces", "vos",
+];
+
+const ENGLISH_STOPWORDS: &[&str] = &[
+    "a", "about", "after", "all", "also", "an", "and", "any", "are", "as", "at", "be", "been",
+    "but", "by", "can", "could", "did", "do", "does", "for", "from", "had", "has", "have", "he",
+    "her", "his", "how", "i", "if", "in", "into", "is", "it", "its", "me", "my", "no", "not", "of",
+    "on", "or", "our", "she", "so", "than", "that", "the", "their", "them", "then", "there",
+    "these", "they", "this", "those", "to", "too", "us", "was", "we", "were", "what", "when",
+    "which", "who", "will", "with", "would", "you", "your",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn same_term(analyzer: &Analyzer, words: &[&str]) {
+        let terms: Vec<Option<String>> = words.iter().map(|w| analyzer.analyze(w)).collect();
+        assert!(terms[0].is_some(), "{} was dropped", words[0]);
+        assert!(
+            terms.iter().all(|term| *term == terms[0]),
+            "{:?} -> {:?}",
+            words,
+            terms
+        );
+    }
+
+    fn other_terms(analyzer: &Analyzer, a: &str, b: &str) {
+        assert_ne!(analyzer.analyze(a), analyzer.analyze(b), "{} and {}", a, b);
+    }
+
+    #[test]
+    fn matches_ao_plurals_with_or_without_accents() {
+        let analyzer = Analyzer::standard(Language::Portuguese);
+        same_term(&analyzer, &["conexões", "conexão", "Conexoes", "conexao"]);
+        same_term(&analyzer, &["ações", "ação", "acoes", "AÇÃO"]);
+        same_term(&analyzer, &["decisões", "decisão", "decisoes"]);
+        same_term(&analyzer, &["cães", "cão", "cao"]);
+        same_term(&analyzer, &["alemães", "alemão"]);
+        same_term(&analyzer, &["mãos", "mão"]);
+        same_term(&analyzer, &["cidadãos", "cidadão"]);
+        same_term(&analyzer, &["mães", "mãe", "mae"]);
+    }
+
+    #[test]
+    fn keeps_other_words_apart_from_ao_plurals() {
+        let analyzer = Analyzer::standard(Language::Portuguese);
+        other_terms(&analyzer, "mães", "mão");
+        other_terms(&analyzer, "mãe", "mão");
+        other_terms(&analyzer, "caos", "cão");
+        other_terms(&analyzer, "shoes", "shão");
+        other_terms(&analyzer, "goes", "gão");
+        other_terms(&analyzer, "heroes", "herão");
+    }
+
+    #[test]
+    fn stems_english_words_with_the_english_stemmer() {
+        let analyzer = Analyzer::standard(Language::English);
+        same_term(&analyzer, &["run", "running", "runs"]);
+        same_term(&analyzer, &["connection", "connections", "Connection"]);
+        // the Portuguese stemmer cuts both to "cas"
+        other_terms(&analyzer, "casa", "case");
+        assert_eq!(analyzer.analyze("time"), Some("time".to_string()));
+    }
+
+    #[test]
+    fn drops_common_words_of_both_languages() {
+        for language in [Language::Portuguese, Language::English] {
+            let analyzer = Analyzer::standard(language);
+            for word in ["de", "Não", "nao", "the", "The", "and"] {
+                assert_eq!(analyzer.analyze(word), None, "{}", word);
+            }
+        }
+    }
+}
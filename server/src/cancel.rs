@@ -0,0 +1,69 @@
+// tells a running search to stop early: the client cancelled it, went away,
+// or the deadline it gave passed
+
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use protocol::SearchStatus;
+
+#[derive(Clone)]
+pub struct Cancellation {
+    // set by a Cancel frame for this request
+    request: Arc<AtomicBool>,
+    // set when the client is gone, shared by every request of the connection
+    connection: Arc<AtomicBool>,
+    received: Instant,
+    deadline: Option<Instant>,
+}
+
+impl Cancellation {
+    pub fn new(connection: &Arc<AtomicBool>) -> Self {
+        Cancellation {
+            request: Arc::new(AtomicBool::new(false)),
+            connection: connection.clone(),
+            received: Instant::now(),
+            deadline: None,
+        }
+    }
+
+    // the deadline counts from when the request was read
+    pub fn with_deadline(mut self, deadline: Option<Duration>) -> Self {
+        self.deadline = deadline.map(|deadline| self.received + deadline);
+        self
+    }
+
+    pub fn cancel(&self) {
+        self.request.store(true, Ordering::Relaxed);
+    }
+
+    // None while the search can go on
+    pub fn reason(&self) -> Option<SearchStatus> {
+        if self.request.load(Ordering::Relaxed) || self.connection.load(Ordering::Relaxed) {
+            Some(SearchStatus::Cancelled)
+        } else if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            Some(SearchStatus::TimedOut)
+        } else {
+            None
+        }
+    }
+
+    // an Interrupted error once the search should stop, to leave it with `?`
+    pub fn check(&self) -> io::Result<()> {
+        match self.reason() {
+            Some(reason) => Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                format!("Search stopped: {:?}", reason),
+            )),
+            None => Ok(()),
+        }
+    }
+}
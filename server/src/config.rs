@@ -0,0 +1,253 @@
+// server settings, from the defaults, a TOML file, environment variables and
+// command line flags, each one overriding the previous
+
+use std::{fs, net::ToSocketAddrs, path::PathBuf, sync::OnceLock, thread};
+
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+
+use crate::{analyzer::Language, query::MAX_CONTEXT};
+
+// read when no --config is given, if it exists
+const DEFAULT_CONFIG_FILE: &str = "server.toml";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // address the server listens on
+    pub address: String,
+    // where the uploaded files are stored
+    pub files_dir: String,
+    // sqlite database with the files and the inverted index
+    pub database: String,
+    // language the words are stemmed in, the files are indexed again when it changes
+    pub language: Language,
+    // bytes read from a connection at a time
+    pub buffer_size: usize,
+    // bytes of a file kept in memory while scanning it
+    pub scan_buffer_size: usize,
+    // files this large are mapped in memory instead of read through the buffer
+    pub mmap_min_size: u64,
+    // threads for file scans and database calls, connections only wait on them
+    pub blocking_threads: usize,
+    // cpu threads shared by every search, each searching one file at a time
+    pub search_threads: usize,
+    // requests served at the same time
+    pub workers: usize,
+    // requests waiting for a worker, the next ones get a busy reply
+    pub queue_size: usize,
+    // frames a search can get ahead of a slow client
+    pub frame_queue_size: usize,
+    // how long a rejected client is told to wait before trying again
+    pub retry_after_ms: u64,
+    // an open connection without requests for this long is closed, freeing its worker
+    pub idle_timeout_secs: u64,
+    // a client that takes longer than this to take a frame is dropped, so it
+    // can't hold the search threads that wait to send it their results
+    pub write_timeout_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: "192.168.0.5:5000".to_string(),
+            files_dir: "./files".to_string(),
+            database: "mygoogle.db".to_string(),
+            language: Language::Portuguese,
+            buffer_size: 16 * 1024,
+            scan_buffer_size: 1024 * 1024,
+            mmap_min_size: 4 * 1024 * 1024,
+            blocking_threads: 64,
+            search_threads: thread::available_parallelism().map_or(4, |n| n.get()),
+            workers: 64,
+            queue_size: 256,
+            frame_queue_size: 64,
+            retry_after_ms: 500,
+            idle_timeout_secs: 60,
+            write_timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Parser)]
+#[command(about = "MyGoogle Search server")]
+struct Args {
+    /// TOML file with the settings [default: server.toml, if it exists]
+    #[arg(long, env = "MYGOOGLE_CONFIG")]
+    config: Option<PathBuf>,
+    /// Print the settings in use and exit
+    #[arg(long)]
+    print_config: bool,
+    /// Address the server listens on
+    #[arg(long, env = "MYGOOGLE_ADDRESS")]
+    address: Option<String>,
+    /// Directory of the uploaded files
+    #[arg(long, env = "MYGOOGLE_FILES_DIR")]
+    files_dir: Option<String>,
+    /// Path of the sqlite database
+    #[arg(long, env = "MYGOOGLE_DATABASE")]
+    database: Option<String>,
+    /// Language the indexed words are stemmed in
+    #[arg(long, env = "MYGOOGLE_LANGUAGE", value_enum)]
+    language: Option<Language>,
+    /// Bytes read from a connection at a time
+    #[arg(long, env = "MYGOOGLE_BUFFER_SIZE")]
+    buffer_size: Option<usize>,
+    /// Bytes of a file kept in memory while scanning it
+    #[arg(long, env = "MYGOOGLE_SCAN_BUFFER_SIZE")]
+    scan_buffer_size: Option<usize>,
+    /// Files of at least this many bytes are mapped in memory to be scanned
+    #[arg(long, env = "MYGOOGLE_MMAP_MIN_SIZE")]
+    mmap_min_size: Option<u64>,
+    /// Threads for file scans and database calls
+    #[arg(long, env = "MYGOOGLE_BLOCKING_THREADS")]
+    blocking_threads: Option<usize>,
+    /// Threads searching files, one per core by default
+    #[arg(long, env = "MYGOOGLE_SEARCH_THREADS")]
+    search_threads: Option<usize>,
+    /// Requests served at the same time
+    #[arg(long, env = "MYGOOGLE_WORKERS")]
+    workers: Option<usize>,
+    /// Requests waiting for a worker before new ones are turned away
+    #[arg(long, env = "MYGOOGLE_QUEUE_SIZE")]
+    queue_size: Option<usize>,
+    /// Result frames a search can get ahead of a slow client
+    #[arg(long, env = "MYGOOGLE_FRAME_QUEUE_SIZE")]
+    frame_queue_size: Option<usize>,
+    /// Milliseconds a busy server asks clients to wait
+    #[arg(long, env = "MYGOOGLE_RETRY_AFTER_MS")]
+    retry_after_ms: Option<u64>,
+    /// Seconds a connection can wait between requests before it is closed
+    #[arg(long, env = "MYGOOGLE_IDLE_TIMEOUT_SECS")]
+    idle_timeout_secs: Option<u64>,
+    /// Seconds a client can take to read a frame before it is dropped
+    #[arg(long, env = "MYGOOGLE_WRITE_TIMEOUT_SECS")]
+    write_timeout_secs: Option<u64>,
+}
+
+impl Config {
+    // reads the settings, None when the server should exit (--print-config)
+    pub fn load() -> Result<Option<Config>, String> {
+        let args = Args::parse();
+        let mut config = match &args.config {
+            Some(path) => Config::read(path)?,
+            None if fs::exists(DEFAULT_CONFIG_FILE).unwrap_or(false) => {
+                Config::read(&PathBuf::from(DEFAULT_CONFIG_FILE))?
+            }
+            None => Config::default(),
+        };
+        // flags and environment variables win over the file
+        if let Some(address) = args.address {
+            config.address = address;
+        }
+        if let Some(files_dir) = args.files_dir {
+            config.files_dir = files_dir;
+        }
+        if let Some(database) = args.database {
+            config.database = database;
+        }
+        if let Some(language) = args.language {
+            config.language = language;
+        }
+        if let Some(buffer_size) = args.buffer_size {
+            config.buffer_size = buffer_size;
+        }
+        if let Some(scan_buffer_size) = args.scan_buffer_size {
+            config.scan_buffer_size = scan_buffer_size;
+        }
+        if let Some(mmap_min_size) = args.mmap_min_size {
+            config.mmap_min_size = mmap_min_size;
+        }
+        if let Some(blocking_threads) = args.blocking_threads {
+            config.blocking_threads = blocking_threads;
+        }
+        if let Some(search_threads) = args.search_threads {
+            config.search_threads = search_threads;
+        }
+        if let Some(workers) = args.workers {
+            config.workers = workers;
+        }
+        if let Some(queue_size) = args.queue_size {
+            config.queue_size = queue_size;
+        }
+        if let Some(frame_queue_size) = args.frame_queue_size {
+            config.frame_queue_size = frame_queue_size;
+        }
+        if let Some(retry_after_ms) = args.retry_after_ms {
+            config.retry_after_ms = retry_after_ms;
+        }
+        if let Some(idle_timeout_secs) = args.idle_timeout_secs {
+            config.idle_timeout_secs = idle_timeout_secs;
+        }
+        if let Some(write_timeout_secs) = args.write_timeout_secs {
+            config.write_timeout_secs = write_timeout_secs;
+        }
+        config.validate()?;
+
+        if args.print_config {
+            print!("{}", toml::to_string(&config).map_err(|e| e.to_string())?);
+            return Ok(None);
+        }
+        Ok(Some(config))
+    }
+
+    fn read(path: &PathBuf) -> Result<Config, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("Error in {}: {}", path.display(), e))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.address.to_socket_addrs().is_err() {
+            return Err(format!("Invalid address: {}", self.address));
+        }
+        if self.files_dir.is_empty() {
+            return Err("files_dir can't be empty".to_string());
+        }
+        if self.database.is_empty() {
+            return Err("database can't be empty".to_string());
+        }
+        let counts = [
+            ("buffer_size", self.buffer_size),
+            ("blocking_threads", self.blocking_threads),
+            ("search_threads", self.search_threads),
+            ("workers", self.workers),
+            ("queue_size", self.queue_size),
+            ("frame_queue_size", self.frame_queue_size),
+        ];
+        for (name, value) in counts {
+            if value == 0 {
+                return Err(format!("{} must be greater than 0", name));
+            }
+        }
+        let timeouts = [
+            ("idle_timeout_secs", self.idle_timeout_secs),
+            ("write_timeout_secs", self.write_timeout_secs),
+        ];
+        for (name, value) in timeouts {
+            if value == 0 {
+                return Err(format!("{} must be greater than 0", name));
+            }
+        }
+        // the largest context on both sides of a match has to fit with room to spare
+        let min_scan_buffer = 4 * MAX_CONTEXT;
+        if self.scan_buffer_size < min_scan_buffer {
+            return Err(format!(
+                "scan_buffer_size must be at least {} bytes",
+                min_scan_buffer
+            ));
+        }
+        Ok(())
+    }
+}
+
+// the settings loaded at startup
+pub fn config() -> &'static Config {
+    CONFIG.get().expect("config not loaded")
+}
+
+pub fn set(config: Config) {
+    CONFIG.set(config).expect("config already loaded");
+}
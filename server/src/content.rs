@@ -0,0 +1,48 @@
+// the bytes of a whole stored file, for searches that need more than a
+// buffer of it at a time
+
+use std::{
+    fs::File,
+    io::{self, Read, Seek},
+    ops::Deref,
+};
+
+use memmap2::Mmap;
+
+use crate::config::config;
+
+pub enum Content {
+    Mapped(Mmap),
+    Read(Vec<u8>),
+}
+
+impl Content {
+    // the whole file, mapped when it is large
+    pub fn load(file: &mut File, size: u64) -> io::Result<Content> {
+        if is_large(size) {
+            // SAFETY: uploads are renamed over the files they replace, so the
+            // server never truncates a file while it is mapped
+            return Ok(Content::Mapped(unsafe { Mmap::map(&*file)? }));
+        }
+        let mut bytes = Vec::with_capacity(size as usize);
+        file.rewind()?;
+        file.read_to_end(&mut bytes)?;
+        Ok(Content::Read(bytes))
+    }
+}
+
+// files mapped instead of read, and searched where they are
+pub fn is_large(size: u64) -> bool {
+    size > 0 && size >= config().mmap_min_size
+}
+
+impl Deref for Content {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Content::Mapped(map) => map,
+            Content::Read(bytes) => bytes,
+        }
+    }
+}
@@ -1,101 +1,250 @@
+#![allow(clippy::module_inception)]
 pub mod database {
-    use sqlite::{Connection, State, Value};
+    use std::collections::HashMap;
 
-    const DB_PATH: &str = "mygoogle.db";
+    use sqlite::{Connection, State};
 
-    pub fn init() -> Result<Connection, sqlite::Error> {
-        let conn = Connection::open(DB_PATH)?;
+    use crate::{
+        analyzer::Language, config::config, index::Token, kind::FileKind, snippet::LineColumn,
+    };
+
+    // bumped whenever the tables or the analyzer change, init brings older
+    // databases up to it
+    const SCHEMA_VERSION: i64 = 5;
+
+    // columns added to the files table since the first release
+    const FILE_COLUMNS: [(&str, &str); 2] = [
+        ("word_count", "INTEGER NOT NULL DEFAULT 0"),
+        ("kind", "TEXT NOT NULL DEFAULT 'text'"),
+    ];
+
+    // writers wait this long for each other instead of failing as busy
+    const BUSY_TIMEOUT_MS: usize = 30_000;
+
+    // every connection goes through here; with WAL searches keep reading
+    // while a file is being indexed
+    fn open() -> Result<Connection, sqlite::Error> {
+        let mut conn = Connection::open(&config().database)?;
+        conn.set_busy_timeout(BUSY_TIMEOUT_MS)?;
+        conn.execute("PRAGMA journal_mode = WAL")?;
+        Ok(conn)
+    }
+
+    pub fn init(language: Language) -> Result<Connection, sqlite::Error> {
+        let conn = open()?;
+        if schema_version(&conn)? < SCHEMA_VERSION {
+            migrate(&conn)?;
+        }
         conn.execute(
             "
             CREATE TABLE IF NOT EXISTS files (
                 id INTEGER PRIMARY KEY,
                 name TEXT NOT NULL,
-                path TEXT NOT NULL
+                path TEXT NOT NULL,
+                word_count INTEGER NOT NULL DEFAULT 0,
+                kind TEXT NOT NULL DEFAULT 'text'
             );
 
             CREATE TABLE IF NOT EXISTS words (
                 id INTEGER PRIMARY KEY,
-                word TEXT NOT NULL
+                word TEXT NOT NULL UNIQUE
             );
-            
+
+            CREATE TABLE IF NOT EXISTS forms (
+                id INTEGER PRIMARY KEY,
+                form TEXT NOT NULL UNIQUE
+            );
+
+            CREATE TABLE IF NOT EXISTS surfaces (
+                id INTEGER PRIMARY KEY,
+                surface TEXT NOT NULL UNIQUE,
+                word_id INTEGER NOT NULL,
+                form_id INTEGER NOT NULL,
+                FOREIGN KEY (word_id) REFERENCES words (id),
+                FOREIGN KEY (form_id) REFERENCES forms (id)
+            );
+
             CREATE TABLE IF NOT EXISTS file_words (
                 id INTEGER PRIMARY KEY,
                 file_id INTEGER NOT NULL,
                 word_id INTEGER NOT NULL,
+                form_id INTEGER NOT NULL,
+                surface_id INTEGER NOT NULL,
                 found_at UNSIGNED BIG INT NOT NULL,
+                byte_start UNSIGNED BIG INT NOT NULL,
+                byte_end UNSIGNED BIG INT NOT NULL,
+                line UNSIGNED BIG INT NOT NULL,
+                col UNSIGNED BIG INT NOT NULL,
                 FOREIGN KEY (file_id) REFERENCES files (id),
-                FOREIGN KEY (word_id) REFERENCES words (id)
+                FOREIGN KEY (word_id) REFERENCES words (id),
+                FOREIGN KEY (form_id) REFERENCES forms (id),
+                FOREIGN KEY (surface_id) REFERENCES surfaces (id)
+            );
+
+            CREATE UNIQUE INDEX IF NOT EXISTS words_word_idx ON words (word);
+            CREATE INDEX IF NOT EXISTS file_words_word_idx ON file_words (word_id, file_id);
+            CREATE INDEX IF NOT EXISTS file_words_file_idx ON file_words (file_id);
+            CREATE UNIQUE INDEX IF NOT EXISTS forms_form_idx ON forms (form);
+            CREATE INDEX IF NOT EXISTS file_words_form_idx ON file_words (form_id, file_id);
+            CREATE INDEX IF NOT EXISTS surfaces_word_idx ON surfaces (word_id);
+            CREATE INDEX IF NOT EXISTS surfaces_form_idx ON surfaces (form_id);
+            CREATE INDEX IF NOT EXISTS file_words_surface_idx ON file_words (surface_id, file_id);
+
+            CREATE TABLE IF NOT EXISTS settings (
+                name TEXT PRIMARY KEY,
+                value TEXT NOT NULL
             );
             ",
         )?;
+        // the terms of another language never match the queries
+        let indexed = setting(&conn, "language")?;
+        if indexed.is_some_and(|indexed| indexed != language.as_str()) {
+            clear_index(&conn)?;
+        }
+        set_setting(&conn, "language", language.as_str())?;
+        conn.execute(format!("PRAGMA user_version = {}", SCHEMA_VERSION))?;
         Ok(conn)
     }
 
-    pub fn insert_file(name: &str, path: &str) -> Result<(), sqlite::Error> {
-        let conn = Connection::open(DB_PATH)?;
-        let query = "INSERT INTO files (name, path) VALUES (?, ?);";
-        let mut statement = conn.prepare(query)?;
+    fn setting(conn: &Connection, name: &str) -> Result<Option<String>, sqlite::Error> {
+        let mut statement = conn.prepare("SELECT value FROM settings WHERE name = ?")?;
+        statement.bind((1, name))?;
+        match statement.next()? {
+            State::Row => Ok(Some(statement.read(0)?)),
+            State::Done => Ok(None),
+        }
+    }
+
+    fn set_setting(conn: &Connection, name: &str, value: &str) -> Result<(), sqlite::Error> {
+        let mut statement =
+            conn.prepare("INSERT OR REPLACE INTO settings (name, value) VALUES (?, ?)")?;
         statement.bind((1, name))?;
-        statement.bind((2, path))?;
+        statement.bind((2, value))?;
         statement.next()?;
         Ok(())
     }
 
-    pub fn insert_or_update_file(name: &str, path: &str) -> Result<(), sqlite::Error> {
-        let conn = Connection::open(DB_PATH)?;
+    // every file is indexed again at startup
+    fn clear_index(conn: &Connection) -> Result<(), sqlite::Error> {
+        conn.execute(
+            "
+            BEGIN TRANSACTION;
+            DELETE FROM file_words;
+            DELETE FROM surfaces;
+            DELETE FROM words;
+            DELETE FROM forms;
+            UPDATE files SET word_count = 0;
+            COMMIT;
+            ",
+        )
+    }
+
+    fn schema_version(conn: &Connection) -> Result<i64, sqlite::Error> {
+        let mut statement = conn.prepare("PRAGMA user_version")?;
+        statement.next()?;
+        statement.read::<i64, usize>(0)
+    }
+
+    // The files table keeps the uploads and gets the columns it is missing.
+    // The index tables only hold what was read from the files, so they are
+    // dropped to be created again, and the files are indexed again at startup.
+    fn migrate(conn: &Connection) -> Result<(), sqlite::Error> {
+        conn.execute("BEGIN TRANSACTION")?;
+        let columns = table_columns(conn, "files")?;
+        // a new database has no tables yet
+        if !columns.is_empty() {
+            for (column, definition) in FILE_COLUMNS {
+                if !columns.iter().any(|name| name == column) {
+                    conn.execute(format!(
+                        "ALTER TABLE files ADD COLUMN {} {}",
+                        column, definition
+                    ))?;
+                }
+            }
+            conn.execute("UPDATE files SET word_count = 0")?;
+        }
+        conn.execute(
+            "
+            DROP TABLE IF EXISTS file_words;
+            DROP TABLE IF EXISTS surfaces;
+            DROP TABLE IF EXISTS words;
+            DROP TABLE IF EXISTS forms;
+            ",
+        )?;
+        conn.execute("COMMIT")
+    }
+
+    fn table_columns(conn: &Connection, table: &str) -> Result<Vec<String>, sqlite::Error> {
+        let mut statement = conn.prepare("SELECT name FROM pragma_table_info(?)")?;
+        statement.bind((1, table))?;
+        let mut columns = Vec::new();
+        while let State::Row = statement.next()? {
+            columns.push(statement.read(0)?);
+        }
+        Ok(columns)
+    }
+
+    pub fn insert_or_update_file(
+        name: &str,
+        path: &str,
+        kind: FileKind,
+    ) -> Result<(), sqlite::Error> {
+        let conn = open()?;
 
-        // Check if the record already exists
-        let mut check_stmt = conn.prepare("SELECT COUNT(*) FROM files WHERE name = ? AND path = ?")?;
-        check_stmt.bind((1, name))?;
-        check_stmt.bind((2, path))?;
+        // Check if the record already exists, the statement is done before
+        // the write so it doesn't keep a snapshot that can't take the lock
+        let exists = {
+            let mut check_stmt =
+                conn.prepare("SELECT COUNT(*) FROM files WHERE name = ? AND path = ?")?;
+            check_stmt.bind((1, name))?;
+            check_stmt.bind((2, path))?;
 
-        // Execute the SELECT statement and get count
-        let exists = match check_stmt.next()? {
-            State::Row => check_stmt.read::<i64, usize>(0)? > 0,
-            _ => false,
+            // Execute the SELECT statement and get count
+            match check_stmt.next()? {
+                State::Row => check_stmt.read::<i64, usize>(0)? > 0,
+                _ => false,
+            }
         };
 
         if exists {
             // Update the existing record
-            let mut update_stmt = conn.prepare("UPDATE files SET path = ? WHERE name = ?")?;
+            let mut update_stmt =
+                conn.prepare("UPDATE files SET path = ?, kind = ? WHERE name = ?")?;
             update_stmt.bind((1, path))?;
-            update_stmt.bind((2, name))?;
+            update_stmt.bind((2, kind.as_str()))?;
+            update_stmt.bind((3, name))?;
             update_stmt.next()?;
         } else {
             // Insert a new record
-            let mut insert_stmt = conn.prepare("INSERT INTO files (name, path) VALUES (?, ?)")?;
+            let mut insert_stmt =
+                conn.prepare("INSERT INTO files (name, path, kind) VALUES (?, ?, ?)")?;
             insert_stmt.bind((1, name))?;
             insert_stmt.bind((2, path))?;
+            insert_stmt.bind((3, kind.as_str()))?;
             insert_stmt.next()?;
         }
 
         Ok(())
     }
 
-    pub fn get_file(name: &str) -> Result<(), sqlite::Error> {
-        let conn = Connection::open(DB_PATH)?;
-        let query = "SELECT name, path FROM files WHERE name = ?";
-        let mut statement = conn.prepare(query)?;
-        statement.bind((1, name))?;
-        while let State::Row = statement.next()? {
-            let name: String = statement.read(0)?;
-            let path: String = statement.read(1)?;
-            println!("name = {}, path = {}", name, path);
-        }
-        Ok(())
-    }
+    // false when no file had that name
+    pub fn delete_file(name: &str) -> Result<bool, sqlite::Error> {
+        let conn = open()?;
+        let mut words_stmt = conn.prepare(
+            "DELETE FROM file_words WHERE file_id IN (SELECT id FROM files WHERE name = ?)",
+        )?;
+        words_stmt.bind((1, name))?;
+        words_stmt.next()?;
 
-    pub fn delete_file(name: &str) -> Result<(), sqlite::Error> {
-        let conn = Connection::open(DB_PATH)?;
         let query = "DELETE FROM files WHERE name = ?";
         let mut statement = conn.prepare(query)?;
         statement.bind((1, name))?;
         statement.next()?;
-        Ok(())
+        Ok(conn.change_count() > 0)
     }
 
     pub fn list_files() -> Result<Vec<(String, String)>, sqlite::Error> {
-        let conn = Connection::open(DB_PATH)?;
+        let conn = open()?;
         let query = "SELECT name, path FROM files";
         let mut statement = conn.prepare(query)?;
         let mut files = Vec::new();
@@ -106,4 +255,310 @@ pub mod database {
         }
         Ok(files)
     }
+
+    // stored text files without indexed words, from before the index or from
+    // a migration, along with the empty ones
+    pub fn unindexed_files() -> Result<Vec<(String, String)>, sqlite::Error> {
+        let conn = open()?;
+        let query = "SELECT name, path FROM files WHERE word_count = 0 AND kind = 'text'";
+        let mut statement = conn.prepare(query)?;
+        let mut files = Vec::new();
+        while let State::Row = statement.next()? {
+            let name: String = statement.read(0)?;
+            let path: String = statement.read(1)?;
+            files.push((name, path));
+        }
+        Ok(files)
+    }
+
+    // replaces every indexed word of the file with the tokens `tokens` hands
+    // to its insert function one by one, gives back how many there were
+    pub fn index_file_words(
+        name: &str,
+        tokens: impl FnOnce(
+            &mut dyn FnMut(Token) -> Result<(), sqlite::Error>,
+        ) -> Result<(), sqlite::Error>,
+    ) -> Result<usize, sqlite::Error> {
+        let conn = open()?;
+
+        // the lookup ends before the transaction, a statement left open would
+        // keep an older snapshot that can't take the write lock
+        let file_id = {
+            let mut file_stmt = conn.prepare("SELECT id FROM files WHERE name = ?")?;
+            file_stmt.bind((1, name))?;
+            match file_stmt.next()? {
+                State::Row => file_stmt.read::<i64, usize>(0)?,
+                _ => return Ok(0),
+            }
+        };
+
+        // takes the write lock now, waiting for another file being indexed
+        conn.execute("BEGIN IMMEDIATE TRANSACTION")?;
+        let mut delete_stmt = conn.prepare("DELETE FROM file_words WHERE file_id = ?")?;
+        delete_stmt.bind((1, file_id))?;
+        delete_stmt.next()?;
+
+        let mut words = Vocabulary::prepare(&conn, "words", "word", &[])?;
+        let mut forms = Vocabulary::prepare(&conn, "forms", "form", &[])?;
+        let mut surfaces =
+            Vocabulary::prepare(&conn, "surfaces", "surface", &["word_id", "form_id"])?;
+        let mut insert_stmt = conn.prepare(
+            "INSERT INTO file_words (file_id, word_id, form_id, surface_id, found_at, byte_start, byte_end, line, col)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )?;
+        let mut count = 0;
+        tokens(&mut |token| {
+            let word_id = words.id(&token.word, &[])?;
+            let form_id = forms.id(&token.form, &[])?;
+            let surface_id = surfaces.id(&token.surface, &[word_id, form_id])?;
+            insert_stmt.reset()?;
+            insert_stmt.bind((1, file_id))?;
+            insert_stmt.bind((2, word_id))?;
+            insert_stmt.bind((3, form_id))?;
+            insert_stmt.bind((4, surface_id))?;
+            insert_stmt.bind((5, token.position as i64))?;
+            insert_stmt.bind((6, token.start as i64))?;
+            insert_stmt.bind((7, token.end as i64))?;
+            insert_stmt.bind((8, token.line_column.line as i64))?;
+            insert_stmt.bind((9, token.line_column.column as i64))?;
+            insert_stmt.next()?;
+            count += 1;
+            Ok(())
+        })?;
+        let mut count_stmt = conn.prepare("UPDATE files SET word_count = ? WHERE id = ?")?;
+        count_stmt.bind((1, count as i64))?;
+        count_stmt.bind((2, file_id))?;
+        count_stmt.next()?;
+        conn.execute("COMMIT")?;
+        Ok(count)
+    }
+
+    // the ids of the words (forms, surfaces) of a file, inserted the first
+    // time they are seen along with the ids they link to
+    struct Vocabulary<'a> {
+        insert_stmt: sqlite::Statement<'a>,
+        id_stmt: sqlite::Statement<'a>,
+        // ids already looked up for this file
+        ids: HashMap<String, i64>,
+    }
+
+    impl<'a> Vocabulary<'a> {
+        fn prepare(
+            conn: &'a Connection,
+            table: &str,
+            column: &str,
+            links: &[&str],
+        ) -> Result<Self, sqlite::Error> {
+            let columns: Vec<&str> = [column].iter().chain(links).copied().collect();
+            Ok(Vocabulary {
+                insert_stmt: conn.prepare(format!(
+                    "INSERT OR IGNORE INTO {} ({}) VALUES ({})",
+                    table,
+                    columns.join(", "),
+                    vec!["?"; columns.len()].join(", ")
+                ))?,
+                id_stmt: conn.prepare(format!("SELECT id FROM {} WHERE {} = ?", table, column))?,
+                ids: HashMap::new(),
+            })
+        }
+
+        fn id(&mut self, text: &str, links: &[i64]) -> Result<i64, sqlite::Error> {
+            if let Some(id) = self.ids.get(text) {
+                return Ok(*id);
+            }
+            self.insert_stmt.reset()?;
+            self.insert_stmt.bind((1, text))?;
+            for (index, link) in links.iter().enumerate() {
+                self.insert_stmt.bind((index + 2, *link))?;
+            }
+            self.insert_stmt.next()?;
+            self.id_stmt.reset()?;
+            self.id_stmt.bind((1, text))?;
+            self.id_stmt.next()?;
+            let id = self.id_stmt.read::<i64, usize>(0)?;
+            self.ids.insert(text.to_string(), id);
+            Ok(id)
+        }
+    }
+
+    // a stored file and the number of words indexed from it
+    pub struct IndexedFile {
+        pub id: i64,
+        pub path: String,
+        pub word_count: u64,
+    }
+
+    // the text files, binary ones have no words and never match a query,
+    // not even one that only excludes words
+    pub fn indexed_files() -> Result<Vec<IndexedFile>, sqlite::Error> {
+        let conn = open()?;
+        let query = "SELECT id, path, word_count FROM files WHERE kind = 'text' ORDER BY id";
+        let mut statement = conn.prepare(query)?;
+        let mut files = Vec::new();
+        while let State::Row = statement.next()? {
+            files.push(IndexedFile {
+                id: statement.read(0)?,
+                path: statement.read(1)?,
+                word_count: statement.read::<i64, usize>(2)? as u64,
+            });
+        }
+        Ok(files)
+    }
+
+    // an occurrence of a word in an indexed file
+    pub struct Posting {
+        pub file_id: i64,
+        // position of the word in the file
+        pub position: u64,
+        pub byte_start: u64,
+        pub byte_end: u64,
+        pub line_column: LineColumn,
+        // the word as it is written there
+        pub text: String,
+    }
+
+    // what `read_postings` reads, from file_words joined with surfaces
+    const POSTING_COLUMNS: &str = "file_words.file_id, file_words.found_at, file_words.byte_start,
+        file_words.byte_end, file_words.line, file_words.col, surfaces.surface";
+
+    // rows read between two calls to the `stop` of a long query
+    const STOP_CHECK_ROWS: usize = 4096;
+
+    // every occurrence of the word, grouped by file and in document order;
+    // with `exact` the word is an accented form instead of a folded word.
+    // Gives up with the postings read so far once `stop` is true.
+    pub fn find_word(
+        word: &str,
+        exact: bool,
+        stop: impl Fn() -> bool,
+    ) -> Result<Vec<Posting>, sqlite::Error> {
+        let conn = open()?;
+        // the words of a file are inserted in document order, so ordering by
+        // id follows the index on (word_id, file_id) without sorting first
+        let (table, column) = if exact {
+            ("forms", "form")
+        } else {
+            ("words", "word")
+        };
+        let query = format!(
+            "
+            SELECT {columns}
+            FROM {table}
+            JOIN file_words ON file_words.{column}_id = {table}.id
+            JOIN surfaces ON surfaces.id = file_words.surface_id
+            WHERE {table}.{column} = ?
+            ORDER BY file_words.file_id, file_words.id",
+            columns = POSTING_COLUMNS,
+            table = table,
+            column = column
+        );
+        let mut statement = conn.prepare(query)?;
+        statement.bind((1, word))?;
+        read_postings(&mut statement, stop)
+    }
+
+    // the occurrences written as one of the surfaces, like `find_word`
+    pub fn find_surfaces(
+        ids: &[i64],
+        stop: impl Fn() -> bool,
+    ) -> Result<Vec<Posting>, sqlite::Error> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let conn = open()?;
+        let query = format!(
+            "
+            SELECT {}
+            FROM file_words
+            JOIN surfaces ON surfaces.id = file_words.surface_id
+            WHERE file_words.surface_id IN ({})
+            ORDER BY file_words.file_id, file_words.id",
+            POSTING_COLUMNS,
+            vec!["?"; ids.len()].join(", ")
+        );
+        let mut statement = conn.prepare(query)?;
+        for (index, id) in ids.iter().enumerate() {
+            statement.bind((index + 1, *id))?;
+        }
+        read_postings(&mut statement, stop)
+    }
+
+    fn read_postings(
+        statement: &mut sqlite::Statement,
+        stop: impl Fn() -> bool,
+    ) -> Result<Vec<Posting>, sqlite::Error> {
+        let mut postings = Vec::new();
+        while let State::Row = statement.next()? {
+            if postings.len() % STOP_CHECK_ROWS == 0 && stop() {
+                break;
+            }
+            postings.push(Posting {
+                file_id: statement.read(0)?,
+                position: statement.read::<i64, usize>(1)? as u64,
+                byte_start: statement.read::<i64, usize>(2)? as u64,
+                byte_end: statement.read::<i64, usize>(3)? as u64,
+                line_column: LineColumn {
+                    line: statement.read::<i64, usize>(4)? as u64,
+                    column: statement.read::<i64, usize>(5)? as u64,
+                },
+                text: statement.read(6)?,
+            });
+        }
+        Ok(postings)
+    }
+
+    // every way the word (or accented form with `exact`) was written in the
+    // indexed files, with the case and accents of the text
+    pub fn surfaces(word: &str, exact: bool) -> Result<Vec<(i64, String)>, sqlite::Error> {
+        let conn = open()?;
+        let query = if exact {
+            "
+            SELECT surfaces.id, surfaces.surface
+            FROM forms
+            JOIN surfaces ON surfaces.form_id = forms.id
+            WHERE forms.form = ?"
+        } else {
+            "
+            SELECT surfaces.id, surfaces.surface
+            FROM words
+            JOIN surfaces ON surfaces.word_id = words.id
+            WHERE words.word = ?"
+        };
+        let mut statement = conn.prepare(query)?;
+        statement.bind((1, word))?;
+        let mut surfaces = Vec::new();
+        while let State::Row = statement.next()? {
+            surfaces.push((statement.read(0)?, statement.read(1)?));
+        }
+        Ok(surfaces)
+    }
+
+    // number of indexed files and their average length in words
+    pub fn corpus_stats() -> Result<(u64, f64), sqlite::Error> {
+        let conn = open()?;
+        let query = "SELECT COUNT(*), COALESCE(AVG(word_count), 0) FROM files WHERE word_count > 0";
+        let mut statement = conn.prepare(query)?;
+        statement.next()?;
+        let documents = statement.read::<i64, usize>(0)? as u64;
+        let avg_length = statement.read::<f64, usize>(1)?;
+        Ok((documents, avg_length))
+    }
+
+    // the words indexed after the one with `after_id`, or the accented forms
+    // with `exact`, with their ids
+    pub fn vocabulary(exact: bool, after_id: i64) -> Result<Vec<(i64, String)>, sqlite::Error> {
+        let conn = open()?;
+        let query = if exact {
+            "SELECT id, form FROM forms WHERE id > ? ORDER BY id"
+        } else {
+            "SELECT id, word FROM words WHERE id > ? ORDER BY id"
+        };
+        let mut statement = conn.prepare(query)?;
+        statement.bind((1, after_id))?;
+        let mut words = Vec::new();
+        while let State::Row = statement.next()? {
+            words.push((statement.read(0)?, statement.read(1)?));
+        }
+        Ok(words)
+    }
 }
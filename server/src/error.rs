@@ -0,0 +1,61 @@
+// the error codes the clients get for the failures of the handlers
+
+use std::io;
+
+use protocol::ErrorCode;
+
+// sqlite result codes
+const SQLITE_BUSY: isize = 5;
+const SQLITE_LOCKED: isize = 6;
+const SQLITE_FULL: isize = 13;
+
+// longest file name most file systems take
+const MAX_NAME_LENGTH: usize = 255;
+// uploads are written in this directory of files_dir until they are complete
+pub const PARTIAL_DIR: &str = ".partial";
+
+pub fn db_error_code(error: &sqlite::Error) -> ErrorCode {
+    match error.code {
+        Some(SQLITE_BUSY | SQLITE_LOCKED) => ErrorCode::Busy,
+        Some(SQLITE_FULL) => ErrorCode::DiskFull,
+        _ => ErrorCode::Database,
+    }
+}
+
+// database errors passed along as io::Error keep their own code
+pub fn io_error_code(error: &io::Error) -> ErrorCode {
+    if let Some(error) = error
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<sqlite::Error>())
+    {
+        return db_error_code(error);
+    }
+    match error.kind() {
+        io::ErrorKind::NotFound => ErrorCode::NotFound,
+        io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded | io::ErrorKind::FileTooLarge => {
+            ErrorCode::DiskFull
+        }
+        io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => {
+            ErrorCode::BadRequest
+        }
+        io::ErrorKind::ResourceBusy => ErrorCode::Busy,
+        _ => ErrorCode::Internal,
+    }
+}
+
+// names are joined to files_dir, so they must stay a single path component
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name == "." || name == ".." || name == PARTIAL_DIR {
+        return Err(format!("Invalid file name: \"{}\"", name));
+    }
+    if name.len() > MAX_NAME_LENGTH {
+        return Err(format!(
+            "File name longer than {} bytes: {}",
+            MAX_NAME_LENGTH, name
+        ));
+    }
+    if name.contains(['/', '\\', '\0']) {
+        return Err(format!("File name can't contain /, \\ or NUL: {}", name));
+    }
+    Ok(())
+}
@@ -0,0 +1,203 @@
+// typo tolerant matching of query terms against the indexed words
+
+use protocol::Fuzziness;
+
+// more edits than this match nearly every short word
+pub const MAX_DISTANCE: usize = 2;
+
+// edits allowed between the term and an indexed word
+pub fn max_distance(fuzziness: Fuzziness, term: &str) -> usize {
+    match fuzziness {
+        Fuzziness::Off => 0,
+        Fuzziness::Auto => match term.chars().count() {
+            0..=2 => 0,
+            3..=5 => 1,
+            _ => MAX_DISTANCE,
+        },
+        Fuzziness::Max(distance) => distance,
+    }
+}
+
+// Levenshtein distance between the words, None when it is over `max`.
+// Only a diagonal band of the matrix is filled since any path outside it
+// already costs more than `max`.
+pub fn distance(a: &str, b: &str, max: usize) -> Option<usize> {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    if a.len().abs_diff(b.len()) > max {
+        return None;
+    }
+    let over = max + 1;
+    let mut previous: Vec<usize> = (0..=b.len()).map(|j| j.min(over)).collect();
+    let mut current = vec![over; b.len() + 1];
+    for i in 1..=a.len() {
+        let from = i.saturating_sub(max).max(1);
+        let to = (i + max).min(b.len());
+        current.fill(over);
+        current[0] = i.min(over);
+        for j in from..=to {
+            let substitution = previous[j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            let deletion = previous[j] + 1;
+            let insertion = current[j - 1] + 1;
+            current[j] = substitution.min(deletion).min(insertion).min(over);
+        }
+        if current.iter().all(|d| *d > max) {
+            return None;
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    let distance = previous[b.len()];
+    if distance <= max {
+        Some(distance)
+    } else {
+        None
+    }
+}
+
+// the indexed words a term can expand to, kept between searches and topped
+// up with the words indexed since
+pub struct Vocabulary {
+    // words by their length in chars, only the lengths within the distance
+    // of a term are compared with it
+    by_length: Vec<Vec<String>>,
+    // id of the last word added, the words table only grows while the
+    // server runs
+    last_id: i64,
+}
+
+impl Vocabulary {
+    pub const fn new() -> Self {
+        Vocabulary {
+            by_length: Vec::new(),
+            last_id: 0,
+        }
+    }
+
+    pub fn last_id(&self) -> i64 {
+        self.last_id
+    }
+
+    // words already added are skipped
+    pub fn add(&mut self, id: i64, word: String) {
+        if id <= self.last_id {
+            return;
+        }
+        self.last_id = id;
+        let length = word.chars().count();
+        if self.by_length.len() <= length {
+            self.by_length.resize_with(length + 1, Vec::new);
+        }
+        self.by_length[length].push(word);
+    }
+
+    // the words within the allowed distance of the term, closest first
+    pub fn expand(&self, term: &str, max: usize) -> Vec<String> {
+        let length = term.chars().count();
+        let lengths = length.saturating_sub(max)..(length + max + 1).min(self.by_length.len());
+        let mut close: Vec<(usize, &String)> = self.by_length[lengths]
+            .iter()
+            .flatten()
+            .filter_map(|word| distance(term, word, max).map(|d| (d, word)))
+            .collect();
+        close.sort();
+        close.into_iter().map(|(_, word)| word.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // full table, to check the banded one against
+    fn levenshtein(a: &str, b: &str) -> usize {
+        let a: Vec<char> = a.chars().collect();
+        let b: Vec<char> = b.chars().collect();
+        let mut row: Vec<usize> = (0..=b.len()).collect();
+        for i in 1..=a.len() {
+            let mut diagonal = row[0];
+            row[0] = i;
+            for j in 1..=b.len() {
+                let above = row[j];
+                row[j] = (diagonal + usize::from(a[i - 1] != b[j - 1]))
+                    .min(row[j - 1] + 1)
+                    .min(above + 1);
+                diagonal = above;
+            }
+        }
+        row[b.len()]
+    }
+
+    fn vocabulary(words: &[&str]) -> Vocabulary {
+        let mut vocabulary = Vocabulary::new();
+        for (id, word) in words.iter().enumerate() {
+            vocabulary.add(id as i64 + 1, word.to_string());
+        }
+        vocabulary
+    }
+
+    #[test]
+    fn measures_edits() {
+        assert_eq!(distance("rust", "rust", 2), Some(0));
+        assert_eq!(distance("rust", "rest", 2), Some(1));
+        assert_eq!(distance("rust", "trust", 2), Some(1));
+        assert_eq!(distance("rust", "rut", 2), Some(1));
+        assert_eq!(distance("rust", "rsut", 2), Some(2));
+        assert_eq!(distance("", "ab", 2), Some(2));
+        assert_eq!(distance("kitten", "sitting", 3), Some(3));
+        assert_eq!(distance("kitten", "sitting", 2), None);
+    }
+
+    #[test]
+    fn counts_chars_not_bytes() {
+        assert_eq!(distance("ação", "acao", 2), Some(2));
+        assert_eq!(distance("ação", "açao", 2), Some(1));
+        assert_eq!(distance("ção", "cão", 2), Some(1));
+    }
+
+    #[test]
+    fn agrees_with_the_full_table() {
+        let words = [
+            "", "a", "ab", "ba", "abc", "acb", "casa", "casas", "caso", "asa", "coisa", "cão",
+            "canção", "sistema", "sistemas", "systems",
+        ];
+        for a in words {
+            for b in words {
+                let full = levenshtein(a, b);
+                for max in 0..=3 {
+                    let expected = (full <= max).then_some(full);
+                    assert_eq!(distance(a, b, max), expected, "{a} {b} {max}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn limits_the_distance_by_length() {
+        assert_eq!(max_distance(Fuzziness::Auto, "ab"), 0);
+        assert_eq!(max_distance(Fuzziness::Auto, "casa"), 1);
+        assert_eq!(max_distance(Fuzziness::Auto, "sistema"), 2);
+        assert_eq!(max_distance(Fuzziness::Max(1), "sistema"), 1);
+    }
+
+    #[test]
+    fn expands_to_the_closest_words_first() {
+        let vocabulary = vocabulary(&["coisa", "casas", "caso", "casa", "asa", "sistema"]);
+        assert_eq!(
+            vocabulary.expand("casa", 1),
+            ["casa", "asa", "casas", "caso"]
+        );
+        assert_eq!(vocabulary.expand("casa", 0), ["casa"]);
+        assert_eq!(vocabulary.expand("sistemas", 1), ["sistema"]);
+        assert!(vocabulary.expand("programa", 2).is_empty());
+    }
+
+    #[test]
+    fn skips_the_words_already_added() {
+        let mut vocabulary = vocabulary(&["casa", "caso"]);
+        assert_eq!(vocabulary.last_id(), 2);
+        vocabulary.add(2, "caso".to_string());
+        vocabulary.add(3, "casas".to_string());
+        assert_eq!(vocabulary.last_id(), 3);
+        assert_eq!(vocabulary.expand("casa", 1), ["casa", "casas", "caso"]);
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fs, io,
+    sync::{RwLock, RwLockReadGuard},
+};
+
+use crate::{
+    analyzer::Analyzer,
+    cancel::Cancellation,
+    content::Content,
+    database::database::{
+        corpus_stats, find_surfaces, find_word, index_file_words, indexed_files, surfaces,
+        vocabulary, IndexedFile, Posting,
+    },
+    fuzzy,
+    kind::FileKind,
+    query::{Document, Hit, Query},
+    rank::{bm25, CorpusStats},
+    snippet::LineColumn,
+};
+use protocol::{Fuzziness, SearchOptions};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+// the vocabularies fuzzy terms expand to, of the words and of the accented forms
+static VOCABULARIES: [RwLock<fuzzy::Vocabulary>; 2] = [
+    RwLock::new(fuzzy::Vocabulary::new()),
+    RwLock::new(fuzzy::Vocabulary::new()),
+];
+
+// a word as stored in the inverted index
+#[derive(Debug, Clone)]
+pub struct Token {
+    // the term given by the analyzer, "Conexões" -> "conexa"
+    pub word: String,
+    // lower case, accents kept
+    pub form: String,
+    // as written, for the searches that tell the case
+    pub surface: String,
+    // position of the word in the document (0, 1, 2, ...)
+    pub position: u64,
+    // byte range of the word in the original text
+    pub start: usize,
+    pub end: usize,
+    // where the word starts
+    pub line_column: LineColumn,
+}
+
+// lower case NFKD without the combining marks, "Ação" -> "acao"
+pub fn fold(text: &str) -> String {
+    without_accents(&text.to_lowercase())
+}
+
+// NFKD without the combining marks, keeping the case, "Ação" -> "Acao"
+pub fn without_accents(text: &str) -> String {
+    text.nfkd().filter(|c| !is_combining_mark(*c)).collect()
+}
+
+// lower case NFKC, so the same accented word always has the same bytes
+pub fn exact_form(text: &str) -> String {
+    surface_form(&text.to_lowercase())
+}
+
+// NFKC keeping the case, "Ação" written with a combining tilde -> "Ação"
+pub fn surface_form(text: &str) -> String {
+    text.nfkc().collect()
+}
+
+// splits the text on every char that can't be part of a word, words dropped
+// by the analyzer still count for the positions of the next ones
+pub fn tokenize(text: &str, analyzer: &Analyzer) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    each_token(text.as_bytes(), analyzer, |token| {
+        tokens.push(token);
+        Ok::<_, Infallible>(())
+    })
+    .ok();
+    tokens
+}
+
+// same as `tokenize`, handing each token to `on_token` as it is read instead
+// of keeping them; invalid UTF-8 separates words and offsets stay exact
+pub fn each_token<E>(
+    bytes: &[u8],
+    analyzer: &Analyzer,
+    mut on_token: impl FnMut(Token) -> Result<(), E>,
+) -> Result<(), E> {
+    // the word being read, as it is in the text
+    let mut word: Option<Token> = None;
+    let mut position = 0;
+    let mut offset = 0;
+    let mut line_column = LineColumn::start();
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            // accents written as separate chars (NFD text) are part of the word
+            if c.is_alphanumeric() || (word.is_some() && is_combining_mark(c)) {
+                let token = word.get_or_insert_with(|| Token {
+                    word: String::new(),
+                    form: String::new(),
+                    surface: String::new(),
+                    position,
+                    start: offset,
+                    end: offset,
+                    line_column,
+                });
+                token.form.push(c);
+                token.end = offset + c.len_utf8();
+            } else if let Some(token) = end_word(&mut word, &mut position, analyzer) {
+                on_token(token)?;
+            }
+            let mut encoded = [0; 4];
+            line_column.advance(c.encode_utf8(&mut encoded).as_bytes());
+            offset += c.len_utf8();
+        }
+        if !chunk.invalid().is_empty() {
+            if let Some(token) = end_word(&mut word, &mut position, analyzer) {
+                on_token(token)?;
+            }
+            line_column.advance(chunk.invalid());
+            offset += chunk.invalid().len();
+        }
+    }
+    match end_word(&mut word, &mut position, analyzer) {
+        Some(token) => on_token(token),
+        None => Ok(()),
+    }
+}
+
+// analyzes the word read so far, None when there was none or the analyzer
+// dropped it
+fn end_word(word: &mut Option<Token>, position: &mut u64, analyzer: &Analyzer) -> Option<Token> {
+    let mut token = word.take()?;
+    *position += 1;
+    let analyzed = analyzer.analyze(&token.form)?;
+    token.word = analyzed;
+    token.surface = surface_form(&token.form);
+    token.form = exact_form(&token.form);
+    Some(token)
+}
+
+// reads the stored file and replaces its entries in the words/file_words
+// tables, the words go to the database as they are read
+pub fn index_file(
+    name: &str,
+    path: &str,
+    kind: FileKind,
+    analyzer: &Analyzer,
+) -> io::Result<usize> {
+    let content = match kind {
+        FileKind::Text => {
+            let mut file = fs::File::open(path)?;
+            let size = file.metadata()?.len();
+            Some(Content::load(&mut file, size)?)
+        }
+        // the words of a binary file would only be noise in the index
+        FileKind::Binary => None,
+    };
+    index_file_words(name, |insert| match &content {
+        Some(content) => each_token(content, analyzer, insert),
+        None => Ok(()),
+    })
+    .map_err(io::Error::other)
+}
+
+// compares the way an occurrence is written with the word typed, a whole word must be the same
+// word, otherwise the other words with its term match as long as their
+// letters have the typed case ("Rust" finds "Rusts" but not "rust")
+fn same_word(typed: &str, text: &str, options: &SearchOptions) -> bool {
+    let normalize = |word: &str| {
+        let word = if options.case_sensitive {
+            word.to_string()
+        } else {
+            word.to_lowercase()
+        };
+        if options.exact_accents {
+            word.nfkc().collect()
+        } else {
+            without_accents(&word)
+        }
+    };
+    let (typed, text) = (normalize(typed), normalize(text));
+    if options.whole_word {
+        return typed == text;
+    }
+    typed
+        .chars()
+        .zip(text.chars())
+        .all(|(a, b)| a.is_uppercase() == b.is_uppercase())
+}
+
+// the vocabulary with the words indexed since the last fuzzy search
+fn fuzzy_vocabulary(exact: bool) -> io::Result<RwLockReadGuard<'static, fuzzy::Vocabulary>> {
+    let lock = &VOCABULARIES[usize::from(exact)];
+    let last_id = lock.read().unwrap().last_id();
+    let words = vocabulary(exact, last_id).map_err(io::Error::other)?;
+    if !words.is_empty() {
+        let mut vocabulary = lock.write().unwrap();
+        for (id, word) in words {
+            vocabulary.add(id, word);
+        }
+    }
+    Ok(lock.read().unwrap())
+}
+
+// a file matching the query and the occurrences that made it match
+pub struct FileMatch {
+    pub file: IndexedFile,
+    pub score: f64,
+    pub hits: Vec<Hit>,
+}
+
+// evaluates the query against the index, best matches first, checking the
+// cancellation between words and between files
+pub fn search(
+    query: &Query,
+    options: &SearchOptions,
+    analyzer: &Analyzer,
+    cancel: &Cancellation,
+) -> io::Result<Vec<FileMatch>> {
+    let fuzziness = options.fuzzy;
+    let expanded;
+    let query = if fuzziness == Fuzziness::Off {
+        query
+    } else {
+        let vocabulary = fuzzy_vocabulary(options.exact_accents)?;
+        expanded = query.clone().expand_fuzzy(&mut |term| {
+            vocabulary.expand(term, fuzzy::max_distance(fuzziness, term))
+        });
+        &expanded
+    };
+
+    // case sensitive and whole word queries have the words as typed, their
+    // indexed term gives the ways it was written and the occurrences of the
+    // ones written like the typed word are read
+    let typed = options.case_sensitive || options.whole_word;
+    let mut postings: HashMap<&str, Vec<Posting>> = HashMap::new();
+    for word in query.words() {
+        if postings.contains_key(word) {
+            continue;
+        }
+        cancel.check()?;
+        let term = if !typed {
+            Some(word.to_string())
+        } else if options.exact_accents {
+            Some(exact_form(word))
+        } else {
+            analyzer.analyze(word)
+        };
+        let stop = || cancel.reason().is_some();
+        let word_postings = match term {
+            Some(term) if typed => {
+                let ids: Vec<i64> = surfaces(&term, options.exact_accents)
+                    .map_err(io::Error::other)?
+                    .into_iter()
+                    .filter(|(_, surface)| same_word(word, surface, options))
+                    .map(|(id, _)| id)
+                    .collect();
+                find_surfaces(&ids, stop).map_err(io::Error::other)?
+            }
+            Some(term) => {
+                find_word(&term, options.exact_accents, stop).map_err(io::Error::other)?
+            }
+            None => Vec::new(),
+        };
+        // the postings are cut short when the search was stopped
+        cancel.check()?;
+        postings.insert(word, word_postings);
+    }
+    let files = indexed_files().map_err(io::Error::other)?;
+    let (documents, avg_length) = corpus_stats().map_err(io::Error::other)?;
+    let stats = CorpusStats {
+        documents,
+        avg_length,
+    };
+
+    // split the postings of each word by file
+    let mut docs: HashMap<i64, Document> = HashMap::new();
+    for (word, word_postings) in &postings {
+        for posting in word_postings {
+            docs.entry(posting.file_id)
+                .or_default()
+                .postings
+                .entry(word)
+                .or_default()
+                .push(posting);
+        }
+    }
+    let empty = Document::default();
+
+    // term frequency of every leaf in every file, to get the document frequencies
+    let leaves = query.positive_leaves();
+    let mut term_freqs: Vec<Vec<u64>> = Vec::new();
+    for leaf in &leaves {
+        let mut freqs = Vec::new();
+        for file in &files {
+            cancel.check()?;
+            freqs.push(match docs.get(&file.id) {
+                Some(doc) => leaf.hits(doc).len() as u64,
+                None => 0,
+            });
+        }
+        term_freqs.push(freqs);
+    }
+    let doc_freqs: Vec<u64> = term_freqs
+        .iter()
+        .map(|freqs| freqs.iter().filter(|tf| **tf > 0).count() as u64)
+        .collect();
+
+    let mut results = Vec::new();
+    for (index, file) in files.into_iter().enumerate() {
+        cancel.check()?;
+        let doc = docs.get(&file.id).unwrap_or(&empty);
+        let Some(mut hits) = query.matches(doc) else {
+            continue;
+        };
+        hits.sort_by_key(|hit| hit.position_start);
+        hits.dedup();
+        if let Some(max) = options.max_occurrences_per_file {
+            hits.truncate(max);
+        }
+        let score = term_freqs
+            .iter()
+            .zip(&doc_freqs)
+            .filter(|(freqs, _)| freqs[index] > 0)
+            .map(|(freqs, df)| bm25(&stats, freqs[index], file.word_count, *df))
+            .sum();
+        results.push(FileMatch { file, score, hits });
+    }
+    let by_score = |a: &FileMatch, b: &FileMatch| b.score.total_cmp(&a.score);
+    // the scores are known for every file, only the best ones are sorted
+    if let Some(limit) = options.limit.filter(|limit| *limit < results.len()) {
+        results.select_nth_unstable_by(limit, by_score);
+        results.truncate(limit);
+    }
+    results.sort_by(by_score);
+    Ok(results)
+}
@@ -0,0 +1,83 @@
+// tells text files from binary ones (images, executables, archives) by their
+// first bytes, binary files are neither indexed nor scanned
+
+use std::{
+    fs::File,
+    io::{self, Read},
+};
+
+// bytes looked at to tell the kind of a file
+const SAMPLE_SIZE: usize = 8 * 1024;
+// share of bytes that can't be read as UTF-8 above which a file is binary,
+// high enough for Latin-1 text to still count as text
+const MAX_INVALID_RATIO: f64 = 0.3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    Text,
+    Binary,
+}
+
+impl FileKind {
+    // as stored in the files table
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FileKind::Text => "text",
+            FileKind::Binary => "binary",
+        }
+    }
+}
+
+// a NUL byte or too much invalid UTF-8 in the first bytes means binary
+pub fn detect(bytes: &[u8]) -> FileKind {
+    let sample = &bytes[..bytes.len().min(SAMPLE_SIZE)];
+    if memchr::memchr(0, sample).is_some() {
+        return FileKind::Binary;
+    }
+    let invalid: usize = sample
+        .utf8_chunks()
+        .map(|chunk| chunk.invalid().len())
+        .sum();
+    if invalid as f64 > sample.len() as f64 * MAX_INVALID_RATIO {
+        FileKind::Binary
+    } else {
+        FileKind::Text
+    }
+}
+
+// reads the first bytes of the file, leaving it past them
+pub fn detect_file(file: &mut File) -> io::Result<FileKind> {
+    let mut sample = Vec::with_capacity(SAMPLE_SIZE);
+    file.by_ref()
+        .take(SAMPLE_SIZE as u64)
+        .read_to_end(&mut sample)?;
+    Ok(detect(&sample))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_text() {
+        assert_eq!(detect(b""), FileKind::Text);
+        assert_eq!(detect("conexões\r\nação\ttab".as_bytes()), FileKind::Text);
+        // "ação" in Latin-1, a few bytes that aren't UTF-8
+        assert_eq!(detect(b"uma a\xe7\xe3o de teste"), FileKind::Text);
+    }
+
+    #[test]
+    fn detects_binary() {
+        assert_eq!(detect(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), FileKind::Binary);
+        assert_eq!(detect(b"text with a \0 in it"), FileKind::Binary);
+        let noise: Vec<u8> = (0..1024).map(|i| 0x80 | (i * 7) as u8).collect();
+        assert_eq!(detect(&noise), FileKind::Binary);
+    }
+
+    #[test]
+    fn looks_only_at_the_first_bytes() {
+        let mut bytes = vec![b'a'; SAMPLE_SIZE];
+        bytes.push(0);
+        assert_eq!(detect(&bytes), FileKind::Text);
+    }
+}
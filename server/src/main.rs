@@ -1,310 +1,981 @@
-use database::database::{insert_or_update_file, list_files};
+use analyzer::Analyzer;
+use cancel::Cancellation;
+use config::{config, Config};
+use content::Content;
+use database::database::{insert_or_update_file, list_files, unindexed_files};
+use error::{db_error_code, io_error_code, validate_name, PARTIAL_DIR};
+use futures::{stream::SplitSink, SinkExt, StreamExt};
+use kind::FileKind;
+use protocol::{
+    Capabilities, Envelope, ErrorCode, FrameCodec, Request, RequestId, Response, SearchMode,
+    SearchOptions, SearchOutput, SearchStatus, CONNECTION_ID,
+};
+use query::Query;
+use rayon::{
+    iter::{IntoParallelRefIterator, ParallelIterator},
+    ThreadPool, ThreadPoolBuilder,
+};
+use scan::{Context, Matcher, ScanEvent};
 use std::{
+    collections::HashMap,
     fs,
-    io::{self, Read, Seek, Write},
-    net::{TcpListener, TcpStream},
-    sync::mpsc::{self, channel},
-    thread,
+    io::{self, Seek},
+    ops::ControlFlow,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, LazyLock,
+    },
     time::{Duration, Instant},
 };
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, Semaphore},
+    task::{self, JoinSet},
+    time,
+};
+use tokio_util::codec::Framed;
 
 use crate::database::database::delete_file;
 
+mod analyzer;
+mod cancel;
+mod config;
+mod content;
 mod database;
-// default msg = command <arg1> <arg2> <arg3> ...
-const SERVER_ADDR: &str = "192.168.0.5:5000";
-const FILES_DIR: &str = "./files";
-const BUFFER_SIZE: usize = 16 * 1024;
-const ACK: &[u8] = b"OK";
-// server command map
-const UPLOAD_CMD: u8 = 1;
-const SEARCH_CMD: u8 = 2;
-const DELETE_CMD: u8 = 3;
-const LIST_CMD: u8 = 4;
-
-fn handle_connection(mut stream: TcpStream) -> io::Result<()> {
-    let mut buf = [0u8; 1]; // Command buffer
-    if let Ok(_) = stream.read_exact(&mut buf) {
-        match buf[0] {
-            UPLOAD_CMD => upload_file(&mut stream),
-            SEARCH_CMD => search_files(&mut stream),
-            DELETE_CMD => delete_file_cmd(&mut stream),
-            LIST_CMD => list_files_cmd(&mut stream),
-            _ => send_message(&mut stream, "Invalid command"),
-        }
-    } else {
-        send_message(&mut stream, "Error receiving command")
+mod error;
+mod fuzzy;
+mod index;
+mod kind;
+mod progress;
+mod query;
+mod rank;
+mod scan;
+mod snippet;
+// a connection during the handshake, see the protocol crate
+type Connection = Framed<TcpStream, FrameCodec<Request, Response>>;
+// and after it, when every frame carries the id of its request
+type Session = Framed<TcpStream, FrameCodec<Envelope<Request>, Envelope<Response>>>;
+// sent to the clients in the handshake
+const BUILD: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+const CAPABILITIES: Capabilities = Capabilities::REGEX
+    .union(Capabilities::FUZZY)
+    .union(Capabilities::EXACT_ACCENTS);
+// time a rejected client has to finish sending its request
+const BUSY_TIMEOUT: Duration = Duration::from_secs(1);
+// a request holds a worker while it is served
+static WORKERS: LazyLock<Semaphore> = LazyLock::new(|| Semaphore::new(config().workers));
+// requests being served or waiting for a worker, the next ones get a busy reply
+static ADMITTED: LazyLock<Semaphore> =
+    LazyLock::new(|| Semaphore::new(config().workers + config().queue_size));
+// turns words into index terms, for the uploaded files and the queries
+static ANALYZER: LazyLock<Analyzer> = LazyLock::new(|| Analyzer::standard(config().language));
+// numbers the partial files, so uploads of the same name don't share one
+static UPLOADS: AtomicU64 = AtomicU64::new(0);
+// the cpu work of every search, a file per thread at a time
+static SEARCH_THREADS: LazyLock<ThreadPool> = LazyLock::new(|| {
+    ThreadPoolBuilder::new()
+        .num_threads(config().search_threads)
+        .thread_name(|index| format!("search-{}", index))
+        .build()
+        .expect("search threads")
+});
+
+async fn handle_connection(stream: TcpStream) -> io::Result<()> {
+    let mut connection = Framed::with_capacity(stream, FrameCodec::new(), config().buffer_size);
+    let idle_timeout = Duration::from_secs(config().idle_timeout_secs);
+    let handshake = time::timeout(idle_timeout, handshake(&mut connection)).await;
+    let Ok(Ok(Some(capabilities))) = handshake else {
+        close_connection(&mut connection).await;
+        return Ok(());
+    };
+
+    let session: Session = connection.map_codec(|_| FrameCodec::new());
+    let (sink, mut stream) = session.split();
+    // every request answers through the same writer
+    let (frames, receiver) = mpsc::channel(config().frame_queue_size);
+    // set once the client can't be reached, every request of it stops
+    let gone = Arc::new(AtomicBool::new(false));
+    let writer = tokio::spawn(write_frames(sink, receiver, gone.clone()));
+    let mut requests = JoinSet::new();
+    let mut running: HashMap<RequestId, Cancellation> = HashMap::new();
+    // where the file data of each running upload goes
+    let mut uploads: HashMap<RequestId, mpsc::Sender<Vec<u8>>> = HashMap::new();
+
+    // requests are read as they come until the client leaves or idles
+    loop {
+        let next = tokio::select! {
+            next = time::timeout(idle_timeout, stream.next()) => next,
+            // the writer gave up on the client
+            _ = frames.closed() => break,
+            Some(finished) = requests.join_next() => {
+                if let Ok(id) = finished {
+                    running.remove(&id);
+                    uploads.remove(&id);
+                }
+                continue;
+            }
+        };
+        let Envelope { id, message } = match next {
+            Ok(Some(Ok(envelope))) => envelope,
+            Ok(Some(Err(e))) => {
+                // the rest of the stream can't be trusted after a bad frame
+                gone.store(true, Ordering::Relaxed);
+                let replies = Replies::new(CONNECTION_ID, &frames);
+                let message = format!("Invalid request: {}", e);
+                send_error(&replies, ErrorCode::BadRequest, &message).await?;
+                break;
+            }
+            Ok(None) => break,
+            Err(_) if running.is_empty() => {
+                println!("Closing idle connection");
+                break;
+            }
+            Err(_) => continue,
+        };
+        let replies = Replies::new(id, &frames);
+        let request = match message {
+            Request::Data(data) => {
+                match uploads.get(&id) {
+                    // a failed upload stops taking its data
+                    Some(upload) => {
+                        upload.send(data).await.ok();
+                    }
+                    None => println!("Dropping data of request {}", id),
+                }
+                continue;
+            }
+            Request::Cancel => {
+                // a request that already finished has nothing to stop
+                if let Some(cancel) = running.get(&id) {
+                    cancel.cancel();
+                }
+                continue;
+            }
+            Request::Hello(_) => {
+                send_error(&replies, ErrorCode::BadRequest, "Handshake already done").await?;
+                continue;
+            }
+            _ if id == CONNECTION_ID || running.contains_key(&id) => {
+                let message = format!("Request id {} can't be used now", id);
+                send_error(&replies, ErrorCode::BadRequest, &message).await?;
+                continue;
+            }
+            request => request,
+        };
+        let Ok(admitted) = ADMITTED.try_acquire() else {
+            println!("Queue full, rejecting request");
+            let message = format!("Server busy, retry after {} ms", config().retry_after_ms);
+            send_error(&replies, ErrorCode::Busy, &message).await?;
+            continue;
+        };
+        let data = match request {
+            Request::Upload { .. } => {
+                let (sender, data) = mpsc::channel(config().frame_queue_size);
+                uploads.insert(id, sender);
+                Some(data)
+            }
+            _ => None,
+        };
+        let cancel = Cancellation::new(&gone);
+        running.insert(id, cancel.clone());
+        requests.spawn(async move {
+            let _admitted = admitted;
+            let Ok(_worker) = WORKERS.acquire().await else {
+                return id;
+            };
+            if let Err(e) = handle_request(&replies, request, data, capabilities, cancel).await {
+                println!("Error handling request {}: {}", id, e);
+            }
+            id
+        });
     }
+
+    // the requests already taken are answered before the connection is closed
+    uploads.clear();
+    while requests.join_next().await.is_some() {}
+    drop(frames);
+    writer.await.map_err(io::Error::other)
 }
 
-fn upload_file(stream: &mut TcpStream) -> io::Result<()> {
-    let name = recv_message(stream).unwrap_or_else(|e| {
-        println!("Error receiving message: {}", e);
-        close_connection(stream);
-        return String::new();
-    });
-    send_ack(stream).unwrap_or_else(|e| {
-        println!("Error sending ACK: {}", e);
-        close_connection(stream);
+// sends the responses of every request of a connection, then closes it
+async fn write_frames(
+    mut sink: SplitSink<Session, Envelope<Response>>,
+    mut frames: mpsc::Receiver<Envelope<Response>>,
+    gone: Arc<AtomicBool>,
+) {
+    let write_timeout = Duration::from_secs(config().write_timeout_secs);
+    while let Some(frame) = frames.recv().await {
+        let error = match time::timeout(write_timeout, sink.send(frame)).await {
+            Ok(Ok(())) => continue,
+            Ok(Err(e)) => e,
+            // a client that stopped reading would keep the searches waiting
+            // on a full queue
+            Err(_) => io::Error::new(io::ErrorKind::TimedOut, "Client stopped reading"),
+        };
+        // the requests still running stop at their next check, and the ones
+        // waiting to send get an error once the receiver is dropped
+        println!("Error sending message: {}", error);
+        gone.store(true, Ordering::Relaxed);
+        return;
+    }
+    sink.close().await.unwrap_or_else(|e| {
+        println!("Error shutting down connection: {}", e);
     });
+}
+
+// the capabilities agreed with the client, None when it was refused
+async fn handshake(connection: &mut Connection) -> io::Result<Option<Capabilities>> {
+    let welcome = match connection.next().await {
+        Some(Ok(Request::Hello(hello))) => match hello.negotiate(BUILD, CAPABILITIES) {
+            Ok(welcome) => welcome,
+            Err(message) => {
+                println!("Refusing client: {}", message);
+                refuse(connection, &message).await?;
+                return Ok(None);
+            }
+        },
+        Some(Ok(_)) => {
+            let message = "Expected a handshake first";
+            refuse(connection, message).await?;
+            return Ok(None);
+        }
+        Some(Err(e)) => {
+            let message = format!("Invalid handshake: {}", e);
+            refuse(connection, &message).await?;
+            return Ok(None);
+        }
+        None => return Ok(None),
+    };
+    let capabilities = welcome.capabilities;
+    connection.send(Response::Welcome(welcome)).await?;
+    Ok(Some(capabilities))
+}
+
+// the handshake failed, sent before the frames take ids
+async fn refuse(connection: &mut Connection, message: &str) -> io::Result<()> {
+    let message = message.to_string();
+    let code = ErrorCode::BadRequest;
+    connection.send(Response::Error { code, message }).await
+}
+
+// answers one request, Err when its responses can't be sent anymore
+async fn handle_request(
+    replies: &Replies,
+    request: Request,
+    data: Option<mpsc::Receiver<Vec<u8>>>,
+    capabilities: Capabilities,
+    cancel: Cancellation,
+) -> io::Result<()> {
+    match (request, data) {
+        (Request::Upload { name, size }, Some(data)) => {
+            upload_file(replies, name, size, data).await
+        }
+        (Request::Search { query, options }, _) => {
+            search_files(replies, query, options, capabilities, cancel).await
+        }
+        (Request::Delete { name }, _) => delete_file_cmd(replies, name).await,
+        (Request::List, _) => list_files_cmd(replies).await,
+        (request, _) => {
+            let message = format!("Unexpected request: {:?}", request);
+            send_error(replies, ErrorCode::BadRequest, &message).await
+        }
+    }
+}
+
+// runs blocking file and database work on the bounded blocking pool
+async fn blocking<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> io::Result<T> {
+    task::spawn_blocking(work).await.map_err(io::Error::other)
+}
 
-    recv_file(stream, &name).unwrap_or_else(|e| {
+async fn upload_file(
+    replies: &Replies,
+    name: String,
+    size: u64,
+    data: mpsc::Receiver<Vec<u8>>,
+) -> io::Result<()> {
+    if let Err(message) = validate_name(&name) {
+        return send_error(replies, ErrorCode::InvalidName, &message).await;
+    }
+    replies.send(Response::Ack).await?;
+
+    if let Err(e) = recv_file(data, &name, size).await {
         println!("Error receiving file: {}", e);
-        close_connection(stream);
-        return 0;
-    });
+        let message = format!("Error receiving file: {}", e);
+        return send_error(replies, io_error_code(&e), &message).await;
+    }
 
-    insert_or_update_file(&name, &format!("{}/{}", FILES_DIR, name)).unwrap_or_else(|e| {
-        println!("Error sending ACK: {}", e);
-        close_connection(stream);
-    });
-    Ok(())
+    let path = format!("{}/{}", config().files_dir, name);
+    let indexed = blocking(move || {
+        let (words, kind) = index_stored_file(&name, &path)?;
+        Ok::<_, io::Error>((words, path, kind))
+    })
+    .await?;
+    let response = match indexed {
+        Ok((words, path, kind)) => {
+            println!(
+                "Indexed {} words from {} file: {}",
+                words,
+                kind.as_str(),
+                path
+            );
+            Response::Indexed {
+                words: words as u64,
+            }
+        }
+        Err(e) => {
+            println!("Error indexing file: {}", e);
+            Response::Error {
+                code: io_error_code(&e),
+                message: format!("Error indexing file: {}", e),
+            }
+        }
+    };
+    replies.send(response).await
 }
 
-fn search_files(stream: &mut TcpStream) -> io::Result<()> {
-    let search_term = recv_message(stream).unwrap_or_else(|e| {
-        println!("Error receiving message: {}", e);
-        close_connection(stream);
-        return String::new();
-    });
-    send_ack(stream).unwrap_or_else(|e| {
-        println!("Error sending ACK: {}", e);
-        close_connection(stream);
-    });
+// records the kind of a stored file and indexes its words
+fn index_stored_file(name: &str, path: &str) -> io::Result<(usize, FileKind)> {
+    let kind = kind::detect_file(&mut fs::File::open(path)?)?;
+    insert_or_update_file(name, path, kind).map_err(io::Error::other)?;
+    let words = index::index_file(name, path, kind, &ANALYZER)?;
+    Ok((words, kind))
+}
+
+// indexes the files stored before the index existed or before a migration
+// dropped it, while requests are already served
+fn index_stored_files() {
+    let files = match unindexed_files() {
+        Ok(files) => files,
+        Err(e) => {
+            println!("Error listing files to index: {}", e);
+            return;
+        }
+    };
+    for (name, path) in files {
+        match index_stored_file(&name, &path) {
+            Ok((words, kind)) => println!(
+                "Indexed {} words from {} file: {}",
+                words,
+                kind.as_str(),
+                path
+            ),
+            Err(e) => println!("Error indexing file {}: {}", path, e),
+        }
+    }
+}
+
+async fn search_files(
+    replies: &Replies,
+    search_term: String,
+    options: SearchOptions,
+    capabilities: Capabilities,
+    cancel: Cancellation,
+) -> io::Result<()> {
+    if let Err(message) = query::validate_options(&options) {
+        return send_error(replies, ErrorCode::BadRequest, &message).await;
+    }
+    let required = options.capabilities();
+    if !capabilities.contains(required) {
+        let message = format!("Search needs {}, agreed on {}", required, capabilities);
+        return send_error(replies, ErrorCode::BadRequest, &message).await;
+    }
+    replies.send(Response::Ack).await?;
 
-    // Iterate over every file in the directory
-    let entries = fs::read_dir(FILES_DIR).unwrap();
     let start_time = Instant::now();
 
-    // search each file
-    for entry in entries {
-        let path = entry.unwrap().path();
-        if path.is_file() {
-            println!("Searching in file: {}", path.display());
-            search_in_file(
-                stream,
-                &path.display().to_string(),
-                search_term
-                    .to_lowercase()
-                    .trim_matches(|c: char| !c.is_alphanumeric()),
-            )
-            .unwrap_or_else(|e| {
-                println!("Error searching in file: {}", e);
-                close_connection(stream);
-                return 0;
+    // the search runs on the blocking pool and hands its frames over to the
+    // writer, it stops at its next check once cancelled or past the deadline
+    let frames = replies.clone();
+    let cancel = cancel.with_deadline(options.deadline);
+    let stop = cancel.clone();
+    let search = task::spawn_blocking(move || run_search(&search_term, &options, &frames, &stop));
+    // the results found so far stay valid, the error is sent before Done
+    let (status, failure) = match search.await {
+        Ok(Ok(())) => (SearchStatus::Complete, None),
+        Ok(Err(e)) => match cancel.reason() {
+            Some(reason) if e.kind() == io::ErrorKind::Interrupted => (reason, None),
+            _ => (
+                SearchStatus::Complete,
+                Some((io_error_code(&e), e.to_string())),
+            ),
+        },
+        Err(e) => (
+            SearchStatus::Complete,
+            Some((ErrorCode::Internal, e.to_string())),
+        ),
+    };
+    if status != SearchStatus::Complete {
+        println!("Search stopped early: {:?}", status);
+    }
+    if let Some((code, e)) = failure {
+        println!("Error searching: {}", e);
+        let message = format!("Error searching: {}", e);
+        replies.send(Response::Error { code, message }).await?;
+    }
+
+    let elapsed = start_time.elapsed();
+    replies.send(Response::Done { elapsed, status }).await
+}
+
+// hands a frame over to the connection writer, from the blocking pool
+fn send_frame(frames: &Replies, frame: Response) -> io::Result<()> {
+    let envelope = Envelope {
+        id: frames.id,
+        message: frame,
+    };
+    frames
+        .frames
+        .blocking_send(envelope)
+        .map_err(|_| connection_closed())
+}
+
+fn run_search(
+    search_term: &str,
+    options: &SearchOptions,
+    frames: &Replies,
+    cancel: &Cancellation,
+) -> io::Result<()> {
+    if options.mode == SearchMode::Index {
+        return match query::parse(search_term, options, &ANALYZER) {
+            Ok(query) => search_index(frames, &query, options, cancel),
+            Err(message) => send_frame(
+                frames,
+                Response::Error {
+                    code: ErrorCode::BadRequest,
+                    message,
+                },
+            ),
+        };
+    }
+    let matcher = if options.mode == SearchMode::Regex {
+        Matcher::regex(search_term, options.case_sensitive, options.whole_word)
+    } else {
+        let patterns = query::literal_patterns(search_term);
+        Matcher::new(&patterns, options.case_sensitive, options.whole_word)
+    };
+    match matcher {
+        Ok(matcher) => {
+            // Iterate over every file in the directory
+            let mut paths = Vec::new();
+            for entry in fs::read_dir(&config().files_dir)? {
+                let path = entry?.path();
+                if path.is_file() {
+                    paths.push(path.display().to_string());
+                }
+            }
+            let job = ScanJob {
+                frames,
+                matcher: &matcher,
+                options,
+                cancel,
+                progress: progress::Tracker::new(),
+                matched_files: AtomicUsize::new(0),
+            };
+            // the files are scanned on the search threads, one buffer per thread
+            SEARCH_THREADS.install(|| {
+                paths.par_iter().try_for_each_init(
+                    || vec![0; config().scan_buffer_size],
+                    |buffer, path| search_in_file(&job, path, buffer).map(|_| ()),
+                )
+            })
+        }
+        Err(message) => send_frame(
+            frames,
+            Response::Error {
+                code: ErrorCode::BadRequest,
+                message,
+            },
+        ),
+    }
+}
+
+fn search_index(
+    frames: &Replies,
+    query: &Query,
+    options: &SearchOptions,
+    cancel: &Cancellation,
+) -> io::Result<()> {
+    let results = index::search(query, options, &ANALYZER, cancel)?;
+
+    // the snippets of each file are read on the search threads, each file
+    // streams its frames through its own bounded channel and they are sent
+    // from here in score order, so files further down wait instead of
+    // piling up their frames
+    SEARCH_THREADS.in_place_scope(|scope| {
+        let mut files = Vec::new();
+        for result in results {
+            let (sender, receiver) = std::sync::mpsc::sync_channel(config().frame_queue_size);
+            scope.spawn(move |_| {
+                // the error comes after the frames sent before it
+                if let Err(e) = send_file_frames(result, options, cancel, &sender) {
+                    sender.send(Err(e)).ok();
+                }
             });
+            files.push(receiver);
+        }
+        // dropping the receivers on an error stops the files still sending
+        for receiver in files {
+            for frame in receiver {
+                send_frame(frames, frame?)?;
+            }
         }
+        Ok(())
+    })
+}
+
+// sends the frames of a file found in the index to its channel
+fn send_file_frames(
+    result: index::FileMatch,
+    options: &SearchOptions,
+    cancel: &Cancellation,
+    sender: &std::sync::mpsc::SyncSender<io::Result<Response>>,
+) -> io::Result<()> {
+    let send = |frame| sender.send(Ok(frame)).map_err(|_| connection_closed());
+    cancel.check()?;
+    let path = &result.file.path;
+    println!("Found in index: {} (score {:.4})", path, result.score);
+    let mut file = fs::File::open(path)?;
+    let file_size = file.metadata()?.len();
+    send(Response::Searching {
+        path: path.clone(),
+        size: file_size,
+    })?;
+    if options.output != SearchOutput::Occurrences {
+        let count = (options.output == SearchOutput::Count).then_some(result.hits.len() as u64);
+        send(Response::Matched {
+            path: path.clone(),
+            score: result.score,
+            count,
+        })?;
+        return send(Response::Progress {
+            path: path.clone(),
+            bytes_read: file_size,
+        });
     }
+    // the lines of a hit can be anywhere before it, byte context is read as needed
+    let context = search_context(options);
+    let content = match context {
+        Context::Lines(_) => Some(Content::load(&mut file, file_size)?),
+        Context::Bytes { .. } => None,
+    };
+    for hit in result.hits {
+        cancel.check()?;
+        let (snippet, lines_before, lines_after) = match &content {
+            Some(content) => {
+                // the file may have changed since it was indexed
+                let end = content.len().min(hit.byte_end as usize);
+                let start = end.min(hit.byte_start as usize);
+                context.around(content, start, end)
+            }
+            None => {
+                let snippet = snippet::read_context(
+                    &mut file,
+                    hit.byte_start,
+                    hit.byte_end,
+                    options.before,
+                    options.after,
+                )?;
+                (snippet, Vec::new(), Vec::new())
+            }
+        };
+        send(Response::Found(protocol::Found {
+            path: path.clone(),
+            byte: hit.byte_start,
+            line: hit.line_column.line,
+            column: hit.line_column.column,
+            score: result.score,
+            positions: Some((hit.position_start, hit.position_end)),
+            matched: hit.matched,
+            snippet,
+            lines_before,
+            lines_after,
+        }))?;
+    }
+    send(Response::Progress {
+        path: path.clone(),
+        bytes_read: file_size,
+    })
+}
 
-    let elapsed_time = start_time.elapsed();
-    send_message(stream, &format!("done: {:?}", elapsed_time)).unwrap_or_else(|e| {
-        println!("Error sending message: {}", e);
-    });
-    send_ack(stream).unwrap_or_else(|e| {
-        println!("Error sending ACK: {}", e);
-        close_connection(stream);
-    });
-    Ok(())
+// whole lines with context_lines, bytes around the match otherwise, and
+// nothing when only the files or counts are sent
+fn search_context(options: &SearchOptions) -> Context {
+    match options.context_lines {
+        _ if options.output != SearchOutput::Occurrences => Context::Bytes {
+            before: 0,
+            after: 0,
+        },
+        Some(count) => Context::Lines(count),
+        None => Context::Bytes {
+            before: options.before,
+            after: options.after,
+        },
+    }
 }
-fn search_in_file(stream: &mut TcpStream, file_name: &str, search_term: &str) -> io::Result<u64> {
+
+// what the files of a scan share while they are searched at the same time
+struct ScanJob<'a> {
+    frames: &'a Replies,
+    matcher: &'a Matcher,
+    options: &'a SearchOptions,
+    cancel: &'a Cancellation,
+    progress: progress::Tracker,
+    // files with a match so far, counted up to the limit
+    matched_files: AtomicUsize,
+}
+
+impl ScanJob<'_> {
+    fn limit(&self) -> usize {
+        self.options.limit.unwrap_or(usize::MAX)
+    }
+
+    fn limit_reached(&self) -> bool {
+        self.matched_files.load(Ordering::Relaxed) >= self.limit()
+    }
+
+    // scanned files are not ranked, the first ones with a match are kept
+    fn add_matched_file(&self) -> bool {
+        self.matched_files.fetch_add(1, Ordering::Relaxed) < self.limit()
+    }
+}
+
+fn search_in_file(job: &ScanJob, file_name: &str, buffer: &mut [u8]) -> io::Result<usize> {
+    job.cancel.check()?;
+    if job.limit_reached() {
+        return Ok(0);
+    }
     // get file in files folder
-    let mut file = std::fs::File::open(file_name)?;
-    let file_size = file.metadata()?.len() as u64;
-    let mut buffer = [0; 1024 * 1024];
-    let overlap = search_term.len();
-
-    let update_interval = Duration::from_millis(500);
-    let mut last_update = Instant::now();
-    let mut total_bytes_read = 0 as u64;
-    send_message(
-        stream,
-        &format!(
-            "searching: {}, {}", // Progress percentage
-            file_name, file_size as u64
-        ),
-    )?;
-    while let Ok(bytes_read) = file.read(&mut buffer) {
-        total_bytes_read += bytes_read as u64;
-        if (last_update.elapsed() > update_interval) {
-            send_message(
-                stream,
-                &format!(
-                    "update: {}, {}", // Progress percentage
-                    file_name, total_bytes_read as u64
-                ),
-            )?;
-            last_update = Instant::now();
+    let mut file = fs::File::open(file_name)?;
+    let file_size = file.metadata()?.len();
+    // large files are searched where they are instead of copied to the buffer,
+    // and whole lines can be anywhere before a match
+    let context = search_context(job.options);
+    let content = if content::is_large(file_size) || matches!(context, Context::Lines(_)) {
+        Some(Content::load(&mut file, file_size)?)
+    } else {
+        None
+    };
+    let kind = match &content {
+        Some(content) => kind::detect(content),
+        None => {
+            let kind = kind::detect_file(&mut file)?;
+            file.rewind()?;
+            kind
         }
+    };
+    if kind == FileKind::Binary {
+        println!("Skipping binary file: {}", file_name);
+        return Ok(0);
+    }
+    println!("Searching in file: {}", file_name);
 
-        let search_term = search_term.to_string();
-
-        let content = String::from_utf8_lossy(&buffer[..bytes_read]);
-        for (index, _) in content
-            .to_lowercase()
-            .match_indices(search_term.to_lowercase().as_str())
-        {
-            let start_index = index;
-            let end_index = std::cmp::min(content.len(), start_index + overlap + 10);
-            let snippet = content[start_index..end_index].to_string();
-            send_message(
-                stream,
-                &format!(
-                    "found: {}, {}, {}", // Progress percentage
-                    file_name, index, snippet
-                ),
-            )?;
+    send_frame(
+        job.frames,
+        Response::Searching {
+            path: file_name.to_string(),
+            size: file_size,
+        },
+    )?;
+    let output = job.options.output;
+    let max_occurrences = job.options.max_occurrences_per_file.unwrap_or(usize::MAX);
+    let mut occurrences = 0;
+    let on_event = |event: ScanEvent| match event {
+        ScanEvent::Progress(total_bytes_read) => {
+            // checked after every buffer, so a large file stops promptly
+            job.cancel.check()?;
+            job.progress
+                .update(file_name, total_bytes_read, |path, bytes_read| {
+                    send_frame(job.frames, Response::Progress { path, bytes_read })
+                })?;
+            // other files already took every place
+            if occurrences == 0 && job.limit_reached() {
+                return Ok(ControlFlow::Break(()));
+            }
+            Ok(ControlFlow::Continue(()))
         }
-
-        if bytes_read == overlap {
-            break;
+        ScanEvent::Found(found) => {
+            job.cancel.check()?;
+            if occurrences == 0 && !job.add_matched_file() {
+                return Ok(ControlFlow::Break(()));
+            }
+            if output == SearchOutput::Occurrences {
+                send_frame(
+                    job.frames,
+                    // scanned files are not ranked and have no word positions
+                    Response::Found(protocol::Found {
+                        path: file_name.to_string(),
+                        byte: found.start,
+                        line: found.line_column.line,
+                        column: found.line_column.column,
+                        score: 0.0,
+                        positions: None,
+                        matched: found.text,
+                        snippet: found.snippet,
+                        lines_before: found.lines_before,
+                        lines_after: found.lines_after,
+                    }),
+                )?;
+            }
+            occurrences += 1;
+            // the rest of the file is left unread once enough were found,
+            // a single one is enough to list the file
+            if occurrences == max_occurrences || output == SearchOutput::FilesWithMatches {
+                Ok(ControlFlow::Break(()))
+            } else {
+                Ok(ControlFlow::Continue(()))
+            }
+        }
+    };
+    match &content {
+        Some(content) => {
+            let step = config().scan_buffer_size;
+            scan::scan_bytes(content, job.matcher, step, context, on_event)?
         }
-        file.seek(std::io::SeekFrom::Current(-(overlap as i64)))?;
+        None => {
+            // whole lines always come with the whole file
+            let (before, after) = match context {
+                Context::Bytes { before, after } => (before, after),
+                Context::Lines(_) => (0, 0),
+            };
+            scan::scan(&mut file, job.matcher, buffer, before, after, on_event)?
+        }
+    };
+    // the last bytes read may still wait for the next batch
+    job.progress
+        .finish(file_name, file_size, |path, bytes_read| {
+            send_frame(job.frames, Response::Progress { path, bytes_read })
+        })?;
+    if occurrences > 0 && output != SearchOutput::Occurrences {
+        let count = (output == SearchOutput::Count).then_some(occurrences as u64);
+        send_frame(
+            job.frames,
+            Response::Matched {
+                path: file_name.to_string(),
+                score: 0.0,
+                count,
+            },
+        )?;
     }
-    Ok(file_size)
+    Ok(occurrences)
 }
 
-fn delete_file_cmd(stream: &mut TcpStream) -> io::Result<()> {
-    let name = recv_message(stream).unwrap_or_else(|e| {
-        println!("Error receiving message: {}", e);
-        close_connection(stream);
-        return String::new();
-    });
-    send_ack(stream).unwrap_or_else(|e| {
-        println!("Error sending ACK: {}", e);
-        close_connection(stream);
-    });
-
-    delete_file(name.as_str()).unwrap_or_else(|e| {
-        println!("Error deleting file from db: {}", e);
-        close_connection(stream);
-    });
+async fn delete_file_cmd(replies: &Replies, name: String) -> io::Result<()> {
+    if let Err(message) = validate_name(&name) {
+        return send_error(replies, ErrorCode::InvalidName, &message).await;
+    }
+    let db_name = name.clone();
+    let in_database = match blocking(move || delete_file(&db_name)).await? {
+        Ok(deleted) => deleted,
+        Err(e) => {
+            println!("Error deleting file from db: {}", e);
+            let message = format!("Error deleting file: {}", e);
+            return send_error(replies, db_error_code(&e), &message).await;
+        }
+    };
 
-    let file_path = format!("{}/{}", FILES_DIR, name);
+    let file_path = format!("{}/{}", config().files_dir, name);
     println!("Deleting file: {}", file_path);
-    match fs::remove_file(&file_path) {
-        Ok(_) => {
-            println!("File deleted: {}", file_path);
-
-            send_ack(stream).unwrap_or_else(|e| {
-                println!("Error sending ACK: {}", e);
-                close_connection(stream);
-            });
+    match tokio::fs::remove_file(&file_path).await {
+        Ok(_) => println!("File deleted: {}", file_path),
+        // a file the index knew of is deleted even if it was already gone
+        Err(e) if e.kind() == io::ErrorKind::NotFound && in_database => {
+            println!("File already gone: {}", file_path)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let message = format!("No such file: {}", name);
+            return send_error(replies, ErrorCode::NotFound, &message).await;
         }
         Err(e) => {
             println!("Error deleting file: {}", e);
-            send_message(stream, &format!("error: {}", e)).unwrap_or_else(|e| {
-                println!("Error sending message: {}", e);
-            });
-            close_connection(stream);
+            let message = format!("Error deleting file: {}", e);
+            return send_error(replies, io_error_code(&e), &message).await;
         }
     }
-    Ok(())
+    replies.send(Response::Ack).await
 }
 
-fn list_files_cmd(stream: &mut TcpStream) -> io::Result<()> {
-    send_ack(stream).unwrap_or_else(|e| {
-        println!("Error sending ACK: {}", e);
-        close_connection(stream);
-    });
-    let db_files = list_files();
+async fn list_files_cmd(replies: &Replies) -> io::Result<()> {
+    let db_files = blocking(list_files).await?;
     match db_files {
         Ok(files) => {
-            for (name, path) in files {
-                println!("Listing files: {}", name);
-                send_message(stream, &format!("file: {}", name).as_str()).unwrap_or_else(|e| {
-                    println!("Error sending message: {}", e);
-                });
-            }
-            send_message(stream, "done:").unwrap_or_else(|e| {
-                println!("Error sending message: {}", e);
-            });
-            send_ack(stream).unwrap_or_else(|e| {
-                println!("Error sending ACK: {}", e);
-                close_connection(stream);
-            });
+            let names: Vec<String> = files.into_iter().map(|(name, _path)| name).collect();
+            println!("Listing {} files", names.len());
+            replies.send(Response::Files { names }).await
         }
         Err(e) => {
             println!("Error listing files: {}", e);
-            close_connection(stream);
+            let message = format!("Error listing files: {}", e);
+            send_error(replies, db_error_code(&e), &message).await
         }
     }
-    Ok(())
 }
 
-fn send_message(stream: &mut TcpStream, message: &str) -> io::Result<()> {
-    let message_len = message.len();
-    let message_len_bytes = message_len.to_be_bytes();
-    stream.write_all(&message_len_bytes)?;
-    stream.flush()?;
-    stream.write_all(message.as_bytes())?;
-    stream.flush()?;
-    Ok(())
+// answers the request with an error, the connection stays open
+async fn send_error(replies: &Replies, code: ErrorCode, message: &str) -> io::Result<()> {
+    let message = message.to_string();
+    replies.send(Response::Error { code, message }).await
 }
 
-fn send_ack(stream: &mut TcpStream) -> io::Result<()> {
-    stream.write_all(ACK)?;
-    stream.flush()?;
-    Ok(())
+// the responses of one request, with its id for the connection writer
+#[derive(Clone)]
+struct Replies {
+    id: RequestId,
+    frames: mpsc::Sender<Envelope<Response>>,
 }
 
-fn recv_message(stream: &mut TcpStream) -> io::Result<String> {
-    let mut length_bytes = [0u8; 8];
-    stream.read_exact(&mut length_bytes)?;
-    let length = u64::from_be_bytes(length_bytes) as usize;
-    let mut buffer = vec![0; length];
-    stream.read_exact(&mut buffer)?;
-    Ok(String::from_utf8(buffer).unwrap())
+impl Replies {
+    fn new(id: RequestId, frames: &mpsc::Sender<Envelope<Response>>) -> Self {
+        Replies {
+            id,
+            frames: frames.clone(),
+        }
+    }
+
+    async fn send(&self, message: Response) -> io::Result<()> {
+        let envelope = Envelope {
+            id: self.id,
+            message,
+        };
+        self.frames
+            .send(envelope)
+            .await
+            .map_err(|_| connection_closed())
+    }
 }
 
-fn recv_file(stream: &mut TcpStream, name: &str) -> io::Result<u32> {
-    let mut length_bytes = [0u8; 8];
-    stream.read_exact(&mut length_bytes)?;
-    let length = u64::from_be_bytes(length_bytes);
+fn connection_closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed")
+}
 
-    let mut file = std::fs::File::create(format!("{}/{}", FILES_DIR, name))?;
+async fn recv_file(data: mpsc::Receiver<Vec<u8>>, name: &str, size: u64) -> io::Result<u64> {
+    // written aside and renamed over the old file once complete, searches
+    // reading or mapping the old one keep it until they are done. Every
+    // upload has its own partial file, the last one complete replaces the file.
+    let partial_dir = format!("{}/{}", config().files_dir, PARTIAL_DIR);
+    tokio::fs::create_dir_all(&partial_dir).await?;
+    let partial = format!(
+        "{}/{}",
+        partial_dir,
+        UPLOADS.fetch_add(1, Ordering::Relaxed)
+    );
+    let path = format!("{}/{}", config().files_dir, name);
+    let received = write_partial(data, &partial, size).await;
+    let renamed = match received {
+        Ok(received) => tokio::fs::rename(&partial, &path).await.map(|_| received),
+        Err(e) => Err(e),
+    };
+    // a partial file is never left behind
+    if renamed.is_err() {
+        tokio::fs::remove_file(&partial).await.ok();
+    }
+    renamed
+}
+
+async fn write_partial(
+    mut data: mpsc::Receiver<Vec<u8>>,
+    partial: &str,
+    size: u64,
+) -> io::Result<u64> {
+    let mut file = tokio::fs::File::create(partial).await?;
     let mut received = 0u64;
-    let mut buffer = [0; BUFFER_SIZE];
-    while received < length {
-        match stream.read(&mut buffer) {
-            Ok(0) => break, // End of file
-            Ok(n) => {
-                println!("Bytes read: {}", n);
-                match file.write_all(&buffer[..n]) {
-                    Ok(_) => (),
-                    Err(e) => return Err(e),
-                }
-                received += n as u64;
-            }
-            Err(e) => {
-                std::fs::remove_file(format!("{}/{}", FILES_DIR, name))?;
-                return Err(e);
-            }
-        };
+    while received < size {
+        // the connection closed before the whole file came
+        let data = data
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        file.write_all(&data).await?;
+        println!("Bytes read: {}", data.len());
+        received += data.len() as u64;
     }
-    // Send an ACK back to the client
-    send_ack(stream)?;
-    Ok(received as u32)
+    file.flush().await?;
+    Ok(received)
+}
+
+async fn close_connection(connection: &mut Connection) {
+    connection.get_mut().shutdown().await.unwrap_or_else(|e| {
+        println!("Error shutting down connection: {}", e);
+    });
 }
 
-fn close_connection(stream: &mut TcpStream) {
-    stream
-        .shutdown(std::net::Shutdown::Both)
+// tells the client to come back later, without reading its request
+async fn reject_busy(stream: TcpStream) -> io::Result<()> {
+    let mut connection: Connection =
+        Framed::with_capacity(stream, FrameCodec::new(), config().buffer_size);
+    let retry_after_ms = config().retry_after_ms;
+    connection.send(Response::Busy { retry_after_ms }).await?;
+    let mut stream = connection.into_inner();
+    stream.shutdown().await?;
+    // closing with unread bytes would reset the connection before the
+    // client reads the reply, so the request is read and dropped
+    let mut buffer = vec![0; config().buffer_size];
+    let drain = async {
+        while stream.read(&mut buffer).await? > 0 {}
+        Ok::<_, io::Error>(())
+    };
+    time::timeout(BUSY_TIMEOUT, drain).await.ok();
+    Ok(())
+}
+
+async fn serve() {
+    let listener = TcpListener::bind(&config().address)
+        .await
         .unwrap_or_else(|e| {
-            println!("Error shutting down connection: {}", e);
+            println!("Error binding to address: {}", e);
+            panic!();
         });
+    println!("Server listening on: {}", config().address);
+    task::spawn_blocking(index_stored_files);
+
+    loop {
+        match listener.accept().await {
+            // idle connections cost nothing, new ones are only turned away
+            // when a request couldn't be taken either
+            Ok((stream, _)) if ADMITTED.available_permits() == 0 => {
+                println!("Queue full, rejecting connection");
+                tokio::spawn(async move {
+                    reject_busy(stream).await.unwrap_or_else(|e| {
+                        println!("Error rejecting connection: {}", e);
+                    });
+                });
+            }
+            Ok((stream, _)) => {
+                tokio::spawn(async move {
+                    handle_connection(stream).await.unwrap_or_else(|e| {
+                        println!("Error handling connection: {}", e);
+                    });
+                });
+            }
+            Err(e) => println!("Error accepting connection: {}", e),
+        }
+    }
 }
 
 fn main() {
-    let listener = TcpListener::bind(SERVER_ADDR).unwrap_or_else(|e| {
-        println!("Error binding to address: {}", e);
+    match Config::load() {
+        Ok(Some(loaded)) => config::set(loaded),
+        Ok(None) => return,
+        Err(e) => {
+            println!("Error loading config: {}", e);
+            std::process::exit(2);
+        }
+    }
+    if let Err(e) = database::database::init(config().language) {
+        println!("Error initializing database: {}", e);
         panic!();
+    }
+    fs::create_dir_all(&config().files_dir).unwrap_or_else(|e| {
+        println!("Error creating files directory: {}", e);
     });
-    println!("Server listening on: {}", SERVER_ADDR);
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        thread::spawn(|| {
-            handle_connection(stream);
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .max_blocking_threads(config().blocking_threads)
+        .build()
+        .unwrap_or_else(|e| {
+            println!("Error starting runtime: {}", e);
+            panic!();
         });
-    }
+    runtime.block_on(serve());
 }
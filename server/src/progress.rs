@@ -0,0 +1,72 @@
+// progress of the files a search reads at the same time, gathered from every
+// search thread and sent together so the frames don't grow with the threads
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// time between two batches of progress frames
+const UPDATE_INTERVAL: Duration = Duration::from_millis(500);
+
+pub struct Tracker {
+    state: Mutex<State>,
+}
+
+struct State {
+    last_sent: Instant,
+    // bytes read of the files updated since the last batch
+    pending: HashMap<String, u64>,
+}
+
+impl Tracker {
+    pub fn new() -> Self {
+        Tracker {
+            state: Mutex::new(State {
+                last_sent: Instant::now(),
+                pending: HashMap::new(),
+            }),
+        }
+    }
+
+    // records the bytes read of a file and sends the batch once the interval
+    // passed, batches are sent holding the lock so a file's last progress
+    // can't be overtaken by an older one
+    pub fn update<E>(
+        &self,
+        path: &str,
+        bytes_read: u64,
+        send: impl FnMut(String, u64) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let mut state = self.state.lock().unwrap();
+        state.pending.insert(path.to_string(), bytes_read);
+        if state.last_sent.elapsed() < UPDATE_INTERVAL {
+            return Ok(());
+        }
+        state.send(send)
+    }
+
+    // records a file as read to the end, whether or not the scan stopped
+    // early, and sends the batch right away
+    pub fn finish<E>(
+        &self,
+        path: &str,
+        size: u64,
+        send: impl FnMut(String, u64) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let mut state = self.state.lock().unwrap();
+        state.pending.insert(path.to_string(), size);
+        state.send(send)
+    }
+}
+
+impl State {
+    fn send<E>(&mut self, mut send: impl FnMut(String, u64) -> Result<(), E>) -> Result<(), E> {
+        self.last_sent = Instant::now();
+        for (path, bytes_read) in self.pending.drain() {
+            send(path, bytes_read)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,759 @@
+use std::collections::HashMap;
+
+use protocol::{Fuzziness, SearchMode, SearchOptions};
+
+use crate::{
+    analyzer::Analyzer, database::database::Posting, fuzzy::MAX_DISTANCE, index::tokenize,
+    snippet::LineColumn,
+};
+
+// search query language:
+//   rust tcp          both words (implicit AND)
+//   rust OR go        any of the words
+//   rust -java        files with rust but without java
+//   "tcp socket"      the exact sequence of words
+//   socket NEAR/5 timeout   both within 5 words of each other
+// common words like "de" and "the" are left out, as they are in the index
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    Term(String),
+    // a term and the indexed words close enough to it
+    Fuzzy(String, Vec<String>),
+    // the words and their positions relative to the first one
+    Phrase(Vec<(u64, String)>),
+    Near(Box<Query>, Box<Query>, u64),
+    Not(Box<Query>),
+    And(Vec<Query>),
+    Or(Vec<Query>),
+}
+
+pub const MAX_CONTEXT: usize = 1024;
+pub const MAX_CONTEXT_LINES: usize = 100;
+
+// rejects options the server can't honor
+pub fn validate_options(options: &SearchOptions) -> Result<(), String> {
+    for bytes in [options.before, options.after] {
+        if bytes > MAX_CONTEXT {
+            return Err(format!(
+                "Context must be a number of bytes up to {}: {}",
+                MAX_CONTEXT, bytes
+            ));
+        }
+    }
+    if options
+        .context_lines
+        .is_some_and(|lines| lines > MAX_CONTEXT_LINES)
+    {
+        return Err(format!(
+            "Context lines must be a number up to {}",
+            MAX_CONTEXT_LINES
+        ));
+    }
+    if let Fuzziness::Max(distance) = options.fuzzy {
+        if distance > MAX_DISTANCE {
+            return Err(format!(
+                "Fuzzy distance must be a number up to {}",
+                MAX_DISTANCE
+            ));
+        }
+    }
+    if options.fuzzy != Fuzziness::Off && options.mode != SearchMode::Index {
+        return Err("Fuzzy search only works with the index".to_string());
+    }
+    if options.fuzzy != Fuzziness::Off && (options.case_sensitive || options.whole_word) {
+        return Err("Fuzzy search can't be case sensitive or whole word".to_string());
+    }
+    // scans compare the bytes as typed, accents are always exact there
+    if options.exact_accents && options.mode != SearchMode::Index {
+        return Err(
+            "Exact accents only work with the index, scans always match accents as typed"
+                .to_string(),
+        );
+    }
+    if options.limit == Some(0) || options.max_occurrences_per_file == Some(0) {
+        return Err("Result limits must be at least 1".to_string());
+    }
+    Ok(())
+}
+
+// words and "quoted text" of a literal search, each searched as typed, the
+// matcher takes care of the case
+pub fn literal_patterns(input: &str) -> Vec<String> {
+    let mut patterns = Vec::new();
+    for (index, part) in input.split('"').enumerate() {
+        // odd parts are inside quotes
+        if index % 2 == 1 {
+            if !part.is_empty() {
+                patterns.push(part.to_string());
+            }
+            continue;
+        }
+        for word in part.split_whitespace() {
+            let word = word.trim_matches(|c: char| !c.is_alphanumeric());
+            if !word.is_empty() {
+                patterns.push(word.to_string());
+            }
+        }
+    }
+    patterns
+}
+
+// an occurrence of a term or phrase in a document
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hit {
+    pub position_start: u64,
+    pub position_end: u64,
+    pub byte_start: u64,
+    pub byte_end: u64,
+    // where the hit starts
+    pub line_column: LineColumn,
+    // the words that matched, as they are written in the file
+    pub matched: String,
+}
+
+// the indexed words of a single document, each in document order
+#[derive(Default)]
+pub struct Document<'a> {
+    pub postings: HashMap<&'a str, Vec<&'a Posting>>,
+}
+
+impl<'a> Document<'a> {
+    fn postings(&self, word: &str) -> &[&'a Posting] {
+        self.postings.get(word).map(|p| p.as_slice()).unwrap_or(&[])
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Lexeme {
+    Word(String),
+    Quoted(String),
+    Minus,
+    Or,
+    Near(u64),
+}
+
+// distance used by NEAR without a number
+const DEFAULT_NEAR_DISTANCE: u64 = 10;
+
+fn lex(input: &str) -> Vec<Lexeme> {
+    let mut lexemes = Vec::new();
+    let mut chars = input.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let phrase: String = chars.by_ref().take_while(|&c| c != '"').collect();
+            lexemes.push(Lexeme::Quoted(phrase));
+        } else if c == '-' {
+            chars.next();
+            // a lone dash is not an exclusion
+            if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+                lexemes.push(Lexeme::Minus);
+            }
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == '"' {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            if word == "OR" {
+                lexemes.push(Lexeme::Or);
+            } else if word == "NEAR" {
+                lexemes.push(Lexeme::Near(DEFAULT_NEAR_DISTANCE));
+            } else if let Some(distance) = word
+                .strip_prefix("NEAR/")
+                .and_then(|n| n.parse::<u64>().ok())
+            {
+                lexemes.push(Lexeme::Near(distance));
+            } else {
+                lexemes.push(Lexeme::Word(word));
+            }
+        }
+    }
+    lexemes
+}
+
+// with `exact_accents` the words keep their accents, to be found by their
+// exact form, and case sensitive or whole word searches keep the words as
+// typed, to be compared with the way each occurrence was written
+pub fn parse(input: &str, options: &SearchOptions, analyzer: &Analyzer) -> Result<Query, String> {
+    let lexemes = lex(input);
+    if lexemes.is_empty() {
+        return Err("Empty query".to_string());
+    }
+    let mut alternatives = Vec::new();
+    for group in lexemes.split(|lexeme| *lexeme == Lexeme::Or) {
+        let mut clauses = Vec::new();
+        // there were words, even if the analyzer dropped all of them
+        let mut has_words = false;
+        let mut negate = false;
+        // distance of a NEAR waiting for its right side
+        let mut near: Option<u64> = None;
+        for lexeme in group {
+            let text = match lexeme {
+                Lexeme::Minus => {
+                    negate = true;
+                    continue;
+                }
+                Lexeme::Near(distance) => {
+                    let has_left = matches!(clauses.last(), Some(q) if !matches!(q, Query::Not(_)));
+                    if !has_left || negate || near.is_some() {
+                        return Err("NEAR needs a term on both sides".to_string());
+                    }
+                    near = Some(*distance);
+                    continue;
+                }
+                Lexeme::Word(text) | Lexeme::Quoted(text) => text,
+                Lexeme::Or => unreachable!(),
+            };
+            has_words = true;
+            let tokens = tokenize(text, analyzer);
+            let first = tokens.first().map_or(0, |t| t.position);
+            let mut words: Vec<(u64, String)> = tokens
+                .into_iter()
+                .map(|t| {
+                    let word = if options.case_sensitive || options.whole_word {
+                        text[t.start..t.end].to_string()
+                    } else if options.exact_accents {
+                        t.form
+                    } else {
+                        t.word
+                    };
+                    (t.position - first, word)
+                })
+                .collect();
+            let clause = match words.len() {
+                0 => {
+                    negate = false;
+                    continue;
+                }
+                1 => Query::Term(words.remove(0).1),
+                _ => Query::Phrase(words),
+            };
+            if let Some(distance) = near.take() {
+                if negate {
+                    return Err("NEAR can't be used with an excluded term".to_string());
+                }
+                let left = clauses.pop().unwrap();
+                clauses.push(Query::Near(Box::new(left), Box::new(clause), distance));
+            } else if negate {
+                clauses.push(Query::Not(Box::new(clause)));
+            } else {
+                clauses.push(clause);
+            }
+            negate = false;
+        }
+        if near.is_some() {
+            return Err("NEAR needs a term on both sides".to_string());
+        }
+        match clauses.len() {
+            0 if has_words => continue,
+            0 => return Err("OR needs a term on both sides".to_string()),
+            1 => alternatives.push(clauses.remove(0)),
+            _ => alternatives.push(Query::And(clauses)),
+        }
+    }
+    match alternatives.len() {
+        0 => Err("Empty query".to_string()),
+        1 => Ok(alternatives.remove(0)),
+        _ => Ok(Query::Or(alternatives)),
+    }
+}
+
+impl Query {
+    // every word the query needs postings for
+    pub fn words(&self) -> Vec<&str> {
+        match self {
+            Query::Term(word) => vec![word.as_str()],
+            Query::Fuzzy(_, words) => words.iter().map(|w| w.as_str()).collect(),
+            Query::Phrase(words) => words.iter().map(|(_, w)| w.as_str()).collect(),
+            Query::Near(left, right, _) => [left.words(), right.words()].concat(),
+            Query::Not(query) => query.words(),
+            Query::And(queries) | Query::Or(queries) => {
+                queries.iter().flat_map(|q| q.words()).collect()
+            }
+        }
+    }
+
+    // replaces each term by the indexed words close to it, excluded terms stay exact
+    pub fn expand_fuzzy(self, expand: &mut impl FnMut(&str) -> Vec<String>) -> Query {
+        match self {
+            Query::Term(term) => {
+                let words = expand(&term);
+                Query::Fuzzy(term, words)
+            }
+            Query::Near(left, right, distance) => Query::Near(
+                Box::new(left.expand_fuzzy(expand)),
+                Box::new(right.expand_fuzzy(expand)),
+                distance,
+            ),
+            Query::And(queries) => Query::And(
+                queries
+                    .into_iter()
+                    .map(|q| q.expand_fuzzy(expand))
+                    .collect(),
+            ),
+            Query::Or(queries) => Query::Or(
+                queries
+                    .into_iter()
+                    .map(|q| q.expand_fuzzy(expand))
+                    .collect(),
+            ),
+            query => query,
+        }
+    }
+
+    // terms, phrases and proximity groups that contribute to the relevance of a file
+    pub fn positive_leaves(&self) -> Vec<&Query> {
+        match self {
+            Query::Term(_) | Query::Fuzzy(..) | Query::Phrase(_) | Query::Near(..) => vec![self],
+            Query::Not(_) => Vec::new(),
+            Query::And(queries) | Query::Or(queries) => {
+                queries.iter().flat_map(|q| q.positive_leaves()).collect()
+            }
+        }
+    }
+
+    // occurrences of a term, phrase or proximity group in the document
+    pub fn hits(&self, doc: &Document) -> Vec<Hit> {
+        match self {
+            Query::Term(word) => term_hits(doc, word),
+            Query::Fuzzy(_, words) => {
+                let mut hits: Vec<Hit> = words.iter().flat_map(|w| term_hits(doc, w)).collect();
+                hits.sort_by_key(|hit| hit.position_start);
+                hits
+            }
+            Query::Phrase(words) => phrase_hits(doc, words),
+            Query::Near(left, right, distance) => {
+                near_hits(&left.hits(doc), &right.hits(doc), *distance)
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    // the hits that make the document match, None if it doesn't
+    pub fn matches(&self, doc: &Document) -> Option<Vec<Hit>> {
+        match self {
+            Query::Term(_) | Query::Fuzzy(..) | Query::Phrase(_) | Query::Near(..) => {
+                let hits = self.hits(doc);
+                if hits.is_empty() {
+                    None
+                } else {
+                    Some(hits)
+                }
+            }
+            Query::Not(query) => match query.matches(doc) {
+                Some(_) => None,
+                None => Some(Vec::new()),
+            },
+            Query::And(queries) => {
+                let mut hits = Vec::new();
+                for query in queries {
+                    hits.extend(query.matches(doc)?);
+                }
+                Some(hits)
+            }
+            Query::Or(queries) => {
+                let mut hits: Option<Vec<Hit>> = None;
+                for query in queries {
+                    if let Some(found) = query.matches(doc) {
+                        hits.get_or_insert_with(Vec::new).extend(found);
+                    }
+                }
+                hits
+            }
+        }
+    }
+}
+
+fn term_hits(doc: &Document, word: &str) -> Vec<Hit> {
+    doc.postings(word)
+        .iter()
+        .map(|p| Hit {
+            position_start: p.position,
+            position_end: p.position,
+            byte_start: p.byte_start,
+            byte_end: p.byte_end,
+            line_column: p.line_column,
+            matched: p.text.clone(),
+        })
+        .collect()
+}
+
+// words of the phrase at the same distances they have in the phrase
+fn phrase_hits(doc: &Document, words: &[(u64, String)]) -> Vec<Hit> {
+    let mut hits = Vec::new();
+    let Some(((_, first), rest)) = words.split_first() else {
+        return hits;
+    };
+    'start: for start in doc.postings(first) {
+        let mut last = *start;
+        let mut matched = start.text.clone();
+        for (offset, word) in rest {
+            let position = start.position + offset;
+            let postings = doc.postings(word);
+            match postings.binary_search_by_key(&position, |p| p.position) {
+                Ok(index) => last = postings[index],
+                Err(_) => continue 'start,
+            }
+            matched.push(' ');
+            matched.push_str(&last.text);
+        }
+        hits.push(Hit {
+            position_start: start.position,
+            position_end: last.position,
+            byte_start: start.byte_start,
+            byte_end: last.byte_end,
+            line_column: start.line_column,
+            matched,
+        });
+    }
+    hits
+}
+
+// pairs of hits, in any order, with at most `distance` words between them
+fn near_hits(left: &[Hit], right: &[Hit], distance: u64) -> Vec<Hit> {
+    let mut hits = Vec::new();
+    for a in left {
+        for b in right {
+            let (first, second) = if a.position_start <= b.position_start {
+                (a, b)
+            } else {
+                (b, a)
+            };
+            if second.position_start > first.position_end + distance + 1 {
+                continue;
+            }
+            hits.push(Hit {
+                position_start: first.position_start,
+                position_end: first.position_end.max(second.position_end),
+                byte_start: first.byte_start,
+                byte_end: first.byte_end.max(second.byte_end),
+                line_column: first.line_column,
+                matched: format!("{} NEAR {}", first.matched, second.matched),
+            });
+        }
+    }
+    hits.sort_by_key(|hit| (hit.position_start, hit.position_end));
+    hits.dedup();
+    hits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::Language;
+
+    // lower case words only, so the terms are the words typed
+    fn plain(input: &str) -> Result<Query, String> {
+        parse(input, &SearchOptions::default(), &Analyzer::new(Vec::new()))
+    }
+
+    fn term(word: &str) -> Query {
+        Query::Term(word.to_string())
+    }
+
+    // the words of a document at their positions, a word every 10 bytes,
+    // written in upper case
+    fn postings(words: &[(&str, &[u64])]) -> HashMap<String, Vec<Posting>> {
+        words
+            .iter()
+            .map(|(word, positions)| {
+                let postings = positions
+                    .iter()
+                    .map(|&position| Posting {
+                        file_id: 1,
+                        position,
+                        byte_start: position * 10,
+                        byte_end: position * 10 + word.len() as u64,
+                        line_column: LineColumn {
+                            line: 1,
+                            column: position * 10 + 1,
+                        },
+                        text: word.to_uppercase(),
+                    })
+                    .collect();
+                (word.to_string(), postings)
+            })
+            .collect()
+    }
+
+    fn document(postings: &HashMap<String, Vec<Posting>>) -> Document<'_> {
+        Document {
+            postings: postings
+                .iter()
+                .map(|(word, postings)| (word.as_str(), postings.iter().collect()))
+                .collect(),
+        }
+    }
+
+    // the positions of the hits that make the document match
+    fn matched(input: &str, doc: &Document) -> Option<Vec<u64>> {
+        let mut hits = plain(input).unwrap().matches(doc)?;
+        hits.sort_by_key(|hit| hit.position_start);
+        Some(hits.iter().map(|hit| hit.position_start).collect())
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(
+            plain("rust tcp"),
+            Ok(Query::And(vec![term("rust"), term("tcp")]))
+        );
+        assert_eq!(
+            plain("rust OR go tcp"),
+            Ok(Query::Or(vec![
+                term("rust"),
+                Query::And(vec![term("go"), term("tcp")])
+            ]))
+        );
+        // only the upper case word is the operator
+        assert_eq!(
+            plain("rust or go"),
+            Ok(Query::And(vec![term("rust"), term("or"), term("go")]))
+        );
+    }
+
+    #[test]
+    fn excludes_terms() {
+        assert_eq!(
+            plain("rust -java"),
+            Ok(Query::And(vec![
+                term("rust"),
+                Query::Not(Box::new(term("java")))
+            ]))
+        );
+        // a lone dash is not an exclusion
+        assert_eq!(
+            plain("rust - go"),
+            Ok(Query::And(vec![term("rust"), term("go")]))
+        );
+    }
+
+    #[test]
+    fn parses_phrases() {
+        assert_eq!(
+            plain("\"TCP socket\""),
+            Ok(Query::Phrase(vec![
+                (0, "tcp".to_string()),
+                (1, "socket".to_string())
+            ]))
+        );
+        // a quoted single word is a term
+        assert_eq!(plain("\"rust\""), Ok(term("rust")));
+    }
+
+    #[test]
+    fn rejects_malformed_queries() {
+        assert_eq!(
+            plain("OR rust"),
+            Err("OR needs a term on both sides".to_string())
+        );
+        assert_eq!(
+            plain("rust OR"),
+            Err("OR needs a term on both sides".to_string())
+        );
+        assert_eq!(plain(""), Err("Empty query".to_string()));
+        assert_eq!(plain("  \"\" "), Err("Empty query".to_string()));
+    }
+
+    #[test]
+    fn evaluates_and_or_not() {
+        let postings = postings(&[("rust", &[0, 5]), ("tcp", &[2])]);
+        let doc = document(&postings);
+        assert_eq!(matched("rust tcp", &doc), Some(vec![0, 2, 5]));
+        assert_eq!(matched("rust java", &doc), None);
+        assert_eq!(matched("java OR tcp", &doc), Some(vec![2]));
+        assert_eq!(matched("java OR go", &doc), None);
+        assert_eq!(matched("rust -tcp", &doc), None);
+        // an excluded word adds no hits
+        assert_eq!(matched("tcp -java", &doc), Some(vec![2]));
+        assert_eq!(matched("-java", &doc), Some(vec![]));
+    }
+
+    #[test]
+    fn parses_near() {
+        assert_eq!(
+            plain("socket NEAR/5 timeout"),
+            Ok(Query::Near(
+                Box::new(term("socket")),
+                Box::new(term("timeout")),
+                5
+            ))
+        );
+        assert_eq!(
+            plain("a NEAR \"b c\" d"),
+            Ok(Query::And(vec![
+                Query::Near(
+                    Box::new(term("a")),
+                    Box::new(Query::Phrase(vec![
+                        (0, "b".to_string()),
+                        (1, "c".to_string())
+                    ])),
+                    DEFAULT_NEAR_DISTANCE
+                ),
+                term("d")
+            ]))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_near() {
+        let near = Err("NEAR needs a term on both sides".to_string());
+        assert_eq!(plain("rust NEAR"), near);
+        assert_eq!(plain("NEAR rust"), near);
+        assert_eq!(plain("-rust NEAR go"), near);
+        assert_eq!(plain("a NEAR NEAR b"), near);
+        assert_eq!(
+            plain("rust NEAR -go"),
+            Err("NEAR can't be used with an excluded term".to_string())
+        );
+    }
+
+    #[test]
+    fn finds_phrases_at_their_word_distances() {
+        let postings = postings(&[
+            ("tcp", &[1, 4, 9]),
+            ("socket", &[2, 6, 10]),
+            ("rede", &[12]),
+        ]);
+        let doc = document(&postings);
+        let hits = plain("\"tcp socket\"").unwrap().hits(&doc);
+        let ranges: Vec<_> = hits
+            .iter()
+            .map(|hit| {
+                (
+                    hit.position_start,
+                    hit.position_end,
+                    hit.byte_start,
+                    hit.byte_end,
+                )
+            })
+            .collect();
+        assert_eq!(ranges, [(1, 2, 10, 26), (9, 10, 90, 106)]);
+        // a dropped word between them keeps its place
+        let phrase = Query::Phrase(vec![(0, "socket".to_string()), (2, "rede".to_string())]);
+        let starts: Vec<u64> = phrase.hits(&doc).iter().map(|h| h.position_start).collect();
+        assert_eq!(starts, [10]);
+        assert_eq!(matched("\"socket tcp\"", &doc), None);
+    }
+
+    #[test]
+    fn finds_near_words_in_any_order() {
+        let postings = postings(&[
+            ("socket", &[0, 20]),
+            ("timeout", &[3, 30]),
+            ("tcp", &[16]),
+            ("port", &[17]),
+        ]);
+        let doc = document(&postings);
+        let ranges = |input: &str| -> Vec<(u64, u64)> {
+            let hits = plain(input).unwrap().hits(&doc);
+            hits.iter()
+                .map(|hit| (hit.position_start, hit.position_end))
+                .collect()
+        };
+        // two words between them
+        assert_eq!(ranges("socket NEAR/2 timeout"), [(0, 3)]);
+        assert_eq!(ranges("socket NEAR/1 timeout"), []);
+        assert_eq!(ranges("timeout NEAR/2 socket"), [(0, 3)]);
+        assert_eq!(ranges("tcp NEAR/3 socket"), [(16, 20)]);
+        assert_eq!(ranges("socket NEAR/10 timeout"), [(0, 3), (20, 30)]);
+        // the distance counts from the end of a phrase
+        assert_eq!(ranges("\"tcp port\" NEAR/2 socket"), [(16, 20)]);
+        assert_eq!(ranges("\"tcp port\" NEAR/1 socket"), []);
+        let hit = &plain("socket NEAR/2 timeout").unwrap().hits(&doc)[0];
+        assert_eq!((hit.byte_start, hit.byte_end), (0, 37));
+    }
+
+    #[test]
+    fn keeps_the_typed_words_for_case_and_whole_word() {
+        let options = SearchOptions {
+            case_sensitive: true,
+            ..SearchOptions::default()
+        };
+        let query = parse("Rust", &options, &Analyzer::standard(Language::Portuguese));
+        assert_eq!(query, Ok(term("Rust")));
+        let options = SearchOptions {
+            whole_word: true,
+            ..SearchOptions::default()
+        };
+        let query = parse(
+            "Conexões",
+            &options,
+            &Analyzer::standard(Language::Portuguese),
+        );
+        assert_eq!(query, Ok(term("Conexões")));
+    }
+
+    #[test]
+    fn phrases_keep_the_positions_of_dropped_words() {
+        let query = parse(
+            "\"socket de rede\"",
+            &SearchOptions::default(),
+            &Analyzer::standard(Language::Portuguese),
+        );
+        let Ok(Query::Phrase(words)) = query else {
+            panic!("not a phrase: {:?}", query);
+        };
+        let offsets: Vec<u64> = words.iter().map(|(offset, _)| *offset).collect();
+        assert_eq!(offsets, [0, 2]);
+    }
+
+    #[test]
+    fn skips_groups_of_common_words() {
+        let query = parse(
+            "the OR rust",
+            &SearchOptions::default(),
+            &Analyzer::standard(Language::Portuguese),
+        );
+        assert_eq!(query, Ok(term("rust")));
+    }
+
+    #[test]
+    fn reports_the_words_as_written() {
+        let postings = postings(&[("sistema", &[0]), ("tcp", &[1]), ("rede", &[3])]);
+        let doc = document(&postings);
+        let matched = |query: Query| -> Vec<String> {
+            query
+                .hits(&doc)
+                .into_iter()
+                .map(|hit| hit.matched)
+                .collect()
+        };
+        assert_eq!(matched(term("sistema")), ["SISTEMA"]);
+        let fuzzy = Query::Fuzzy("sistem".to_string(), vec!["sistema".to_string()]);
+        assert_eq!(matched(fuzzy), ["SISTEMA"]);
+        assert_eq!(matched(plain("\"sistema tcp\"").unwrap()), ["SISTEMA TCP"]);
+        assert_eq!(
+            matched(plain("rede NEAR/2 sistema").unwrap()),
+            ["SISTEMA NEAR REDE"]
+        );
+    }
+
+    #[test]
+    fn rejects_fuzzy_distances_above_two() {
+        let options = |fuzzy| SearchOptions {
+            fuzzy,
+            ..SearchOptions::default()
+        };
+        assert!(validate_options(&options(Fuzziness::Max(2))).is_ok());
+        assert!(validate_options(&options(Fuzziness::Max(3))).is_err());
+    }
+
+    #[test]
+    fn rejects_exact_accents_in_scans() {
+        let options = |mode| SearchOptions {
+            mode,
+            exact_accents: true,
+            ..SearchOptions::default()
+        };
+        assert!(validate_options(&options(SearchMode::Index)).is_ok());
+        assert!(validate_options(&options(SearchMode::Literal)).is_err());
+        assert!(validate_options(&options(SearchMode::Regex)).is_err());
+    }
+}
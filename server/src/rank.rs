@@ -0,0 +1,75 @@
+// Okapi BM25 scoring over the inverted index
+// https://en.wikipedia.org/wiki/Okapi_BM25
+
+// term frequency saturation
+const K1: f64 = 1.2;
+// how much the document length normalizes the term frequency
+const B: f64 = 0.75;
+
+pub struct CorpusStats {
+    // number of indexed documents
+    pub documents: u64,
+    // average document length in words
+    pub avg_length: f64,
+}
+
+// inverse document frequency of a term found in `doc_freq` documents
+pub fn idf(stats: &CorpusStats, doc_freq: u64) -> f64 {
+    let n = stats.documents as f64;
+    let df = doc_freq as f64;
+    (1.0 + (n - df + 0.5) / (df + 0.5)).ln()
+}
+
+// score contribution of a single term for one document
+pub fn bm25(stats: &CorpusStats, term_freq: u64, doc_length: u64, doc_freq: u64) -> f64 {
+    let tf = term_freq as f64;
+    let avg_length = if stats.avg_length > 0.0 {
+        stats.avg_length
+    } else {
+        1.0
+    };
+    let norm = 1.0 - B + B * (doc_length as f64 / avg_length);
+    idf(stats, doc_freq) * (tf * (K1 + 1.0)) / (tf + K1 * norm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATS: CorpusStats = CorpusStats {
+        documents: 100,
+        avg_length: 50.0,
+    };
+
+    #[test]
+    fn ranks_more_occurrences_higher_with_less_gain() {
+        let scores: Vec<f64> = (1..=4).map(|tf| bm25(&STATS, tf, 50, 10)).collect();
+        assert!(scores.windows(2).all(|pair| pair[0] < pair[1]));
+        // each occurrence adds less than the one before it
+        assert!(scores[1] - scores[0] > scores[3] - scores[2]);
+        assert!(bm25(&STATS, 1000, 50, 10) < idf(&STATS, 10) * (K1 + 1.0));
+    }
+
+    #[test]
+    fn ranks_shorter_files_higher() {
+        assert!(bm25(&STATS, 3, 20, 10) > bm25(&STATS, 3, 50, 10));
+        assert!(bm25(&STATS, 3, 50, 10) > bm25(&STATS, 3, 500, 10));
+    }
+
+    #[test]
+    fn ranks_rarer_terms_higher() {
+        assert!(bm25(&STATS, 1, 50, 1) > bm25(&STATS, 1, 50, 10));
+        assert!(bm25(&STATS, 1, 50, 10) > bm25(&STATS, 1, 50, 100));
+        // a term in every file still counts a little
+        assert!(idf(&STATS, 100) > 0.0);
+    }
+
+    #[test]
+    fn scores_an_empty_corpus() {
+        let empty = CorpusStats {
+            documents: 0,
+            avg_length: 0.0,
+        };
+        assert!(bm25(&empty, 1, 0, 0).is_finite());
+    }
+}
@@ -0,0 +1,646 @@
+use std::{
+    io::{self, Read},
+    ops::{ControlFlow, Range},
+};
+
+use aho_corasick::{AhoCorasick, MatchKind};
+use memchr::memmem;
+use regex::bytes::{Regex, RegexBuilder};
+use unicode_normalization::char::is_combining_mark;
+
+use crate::snippet::{self, LineColumn};
+
+// bytes of the longest UTF-8 char, looked at on both sides of a match to
+// tell if it is a whole word
+const MAX_CHAR_LEN: usize = 4;
+
+// finds every pattern in raw bytes, ignoring case like the index does unless
+// the search is case sensitive
+pub struct Matcher {
+    finder: Finder,
+    // matches next to a letter, a digit or '_' are skipped, like grep -w
+    whole_word: bool,
+}
+
+enum Finder {
+    // a single pattern with no letters to fold, or a case sensitive one
+    Single(Box<memmem::Finder<'static>>),
+    // many patterns, or one that needs case folding
+    Multi {
+        automaton: AhoCorasick,
+        max_len: usize,
+    },
+    // regular expression, matches can be any size so the whole file is searched at once
+    Regex(Box<Regex>),
+}
+
+// a pattern found while scanning a file
+pub struct Match {
+    // absolute byte offset in the file
+    pub start: u64,
+    pub line_column: LineColumn,
+    // the matched text
+    pub text: String,
+    // the match with the requested context around it, its whole lines with
+    // Context::Lines
+    pub snippet: String,
+    // the lines around the match with Context::Lines
+    pub lines_before: Vec<String>,
+    pub lines_after: Vec<String>,
+}
+
+// what is sent around each match
+#[derive(Debug, Clone, Copy)]
+pub enum Context {
+    Bytes { before: usize, after: usize },
+    // the lines of the match and this many lines before and after them
+    Lines(usize),
+}
+
+impl Context {
+    // the snippet of bytes[start..end] and the lines before and after it
+    pub fn around(
+        self,
+        bytes: &[u8],
+        start: usize,
+        end: usize,
+    ) -> (String, Vec<String>, Vec<String>) {
+        match self {
+            Context::Bytes { before, after } => (
+                snippet::context(bytes, start, end, before, after),
+                Vec::new(),
+                Vec::new(),
+            ),
+            Context::Lines(count) => {
+                let lines = snippet::lines(bytes, start, end, count);
+                (lines.matched, lines.before, lines.after)
+            }
+        }
+    }
+}
+
+pub enum ScanEvent {
+    // total bytes read so far, sent after every read
+    Progress(u64),
+    Found(Match),
+}
+
+impl Matcher {
+    pub fn new(
+        patterns: &[String],
+        case_sensitive: bool,
+        whole_word: bool,
+    ) -> Result<Matcher, String> {
+        if patterns.iter().any(|p| p.is_empty()) || patterns.is_empty() {
+            return Err("Empty search pattern".to_string());
+        }
+        if let [pattern] = patterns {
+            if case_sensitive || pattern.to_lowercase() == pattern.to_uppercase() {
+                let finder = memmem::Finder::new(pattern.as_bytes()).into_owned();
+                return Ok(Matcher {
+                    finder: Finder::Single(Box::new(finder)),
+                    whole_word,
+                });
+            }
+        }
+
+        // ascii letters are folded by the automaton, other letters need their
+        // upper case form as an extra pattern (ação / AÇÃO)
+        let mut variants = Vec::new();
+        for pattern in patterns {
+            if case_sensitive {
+                variants.push(pattern.clone());
+                continue;
+            }
+            let lower = pattern.to_lowercase();
+            let upper = pattern.to_uppercase();
+            if !lower.is_ascii() && lower != upper {
+                variants.push(upper);
+            }
+            variants.push(lower);
+        }
+        let max_len = variants.iter().map(|v| v.len()).max().unwrap_or(0);
+        let automaton = AhoCorasick::builder()
+            .ascii_case_insensitive(!case_sensitive)
+            .match_kind(MatchKind::LeftmostLongest)
+            .build(&variants)
+            .map_err(|e| e.to_string())?;
+        Ok(Matcher {
+            finder: Finder::Multi { automaton, max_len },
+            whole_word,
+        })
+    }
+
+    pub fn regex(pattern: &str, case_sensitive: bool, whole_word: bool) -> Result<Matcher, String> {
+        let regex = RegexBuilder::new(pattern)
+            .case_insensitive(!case_sensitive)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Matcher {
+            finder: Finder::Regex(Box::new(regex)),
+            whole_word,
+        })
+    }
+
+    fn is_regex(&self) -> bool {
+        matches!(self.finder, Finder::Regex(_))
+    }
+
+    fn max_len(&self) -> usize {
+        match &self.finder {
+            Finder::Single(finder) => finder.needle().len(),
+            Finder::Multi { max_len, .. } => *max_len,
+            Finder::Regex(_) => 0,
+        }
+    }
+
+    // bytes needed on both sides of a match to tell if it is a whole word
+    fn margin(&self) -> usize {
+        if self.whole_word {
+            MAX_CHAR_LEN
+        } else {
+            0
+        }
+    }
+
+    // calls `found(start, end)` for every match in bytes[range], until it
+    // breaks, the bytes around the range tell the whole words
+    fn find_all(
+        &self,
+        bytes: &[u8],
+        range: Range<usize>,
+        mut found: impl FnMut(usize, usize) -> ControlFlow<()>,
+    ) {
+        let offset = range.start;
+        let haystack = &bytes[range];
+        let mut found = |start: usize, end: usize| {
+            let (start, end) = (offset + start, offset + end);
+            if self.whole_word && !is_whole_word(bytes, start, end) {
+                return ControlFlow::Continue(());
+            }
+            found(start, end)
+        };
+        match &self.finder {
+            Finder::Single(finder) => {
+                let len = finder.needle().len();
+                for start in finder.find_iter(haystack) {
+                    if found(start, start + len).is_break() {
+                        return;
+                    }
+                }
+            }
+            Finder::Multi { automaton, .. } => {
+                for m in automaton.find_iter(haystack) {
+                    if found(m.start(), m.end()).is_break() {
+                        return;
+                    }
+                }
+            }
+            Finder::Regex(regex) => {
+                // an empty match is not an occurrence of anything
+                for m in regex.find_iter(haystack).filter(|m| !m.is_empty()) {
+                    if found(m.start(), m.end()).is_break() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// letters, digits and '_' make up words, with the accents written as
+// separate chars
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || is_combining_mark(c)
+}
+
+// the match has no word char right before or after it, bytes that aren't
+// UTF-8 separate words
+fn is_whole_word(bytes: &[u8], start: usize, end: usize) -> bool {
+    let before = &bytes[start.saturating_sub(MAX_CHAR_LEN)..start];
+    let previous = before
+        .utf8_chunks()
+        .last()
+        .filter(|chunk| chunk.invalid().is_empty())
+        .and_then(|chunk| chunk.valid().chars().next_back());
+    let after = &bytes[end..(end + MAX_CHAR_LEN).min(bytes.len())];
+    let next = after
+        .utf8_chunks()
+        .next()
+        .and_then(|chunk| chunk.valid().chars().next());
+    !previous.is_some_and(is_word_char) && !next.is_some_and(is_word_char)
+}
+
+// Streams the reader through the buffer once, searching every pattern at the
+// same time. The last bytes of each read are kept at the start of the buffer
+// so matches crossing two reads, and their context, are still found without
+// seeking back. The scan stops early once `on_event` breaks.
+pub fn scan<R: Read>(
+    reader: &mut R,
+    matcher: &Matcher,
+    buffer: &mut [u8],
+    before: usize,
+    after: usize,
+    mut on_event: impl FnMut(ScanEvent) -> io::Result<ControlFlow<()>>,
+) -> io::Result<u64> {
+    if matcher.is_regex() {
+        return scan_whole(reader, matcher, before, after, on_event);
+    }
+    // whole words need a char after a match before it is reported, and
+    // one before it once the match is kept for the next read
+    let margin = matcher.margin();
+    let after_match = after.max(margin);
+    let keep_max = before + margin + matcher.max_len() - 1 + after_match;
+    if buffer.len() <= keep_max {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Search pattern and context are larger than the scan buffer",
+        ));
+    }
+    // absolute offset of buffer[0]
+    let mut base = 0u64;
+    let mut filled = 0;
+    // matches ending up to here were already reported (relative to buffer[0])
+    let mut reported = 0;
+    // line and column of `counted`, the absolute offset lines were counted to
+    let mut line_column = LineColumn::start();
+    let mut counted = 0u64;
+    let mut total_bytes_read = 0u64;
+    loop {
+        let bytes_read = reader.read(&mut buffer[filled..])?;
+        filled += bytes_read;
+        total_bytes_read += bytes_read as u64;
+        let at_end = bytes_read == 0;
+        if !at_end && on_event(ScanEvent::Progress(total_bytes_read))?.is_break() {
+            return Ok(total_bytes_read);
+        }
+
+        // matches closer to the end than `after` wait for the next read
+        let commit = if at_end {
+            filled
+        } else {
+            filled.saturating_sub(after_match)
+        };
+        let haystack = &buffer[..filled];
+        let mut result = Ok(ControlFlow::Continue(()));
+        matcher.find_all(haystack, 0..filled, |start, end| {
+            if end <= reported || end > commit {
+                return ControlFlow::Continue(());
+            }
+            let absolute_start = base + start as u64;
+            // overlaps the last reported match
+            if absolute_start < counted {
+                return ControlFlow::Continue(());
+            }
+            line_column.advance(&haystack[(counted - base) as usize..start]);
+            counted = absolute_start;
+            result = on_event(ScanEvent::Found(Match {
+                start: absolute_start,
+                line_column,
+                text: String::from_utf8_lossy(&haystack[start..end]).to_string(),
+                snippet: snippet::context(haystack, start, end, before, after),
+                lines_before: Vec::new(),
+                lines_after: Vec::new(),
+            }));
+            flow(&result)
+        });
+        if result?.is_break() || at_end {
+            break;
+        }
+        reported = reported.max(commit);
+
+        let keep = keep_max.min(filled);
+        let dropped = filled - keep;
+        if base + (dropped as u64) > counted {
+            line_column.advance(&buffer[(counted - base) as usize..dropped]);
+            counted = base + dropped as u64;
+        }
+        buffer.copy_within(dropped..filled, 0);
+        base += dropped as u64;
+        filled = keep;
+        reported -= dropped;
+    }
+    Ok(total_bytes_read)
+}
+
+// reads the whole file before searching, for matchers without a maximum length
+fn scan_whole<R: Read>(
+    reader: &mut R,
+    matcher: &Matcher,
+    before: usize,
+    after: usize,
+    on_event: impl FnMut(ScanEvent) -> io::Result<ControlFlow<()>>,
+) -> io::Result<u64> {
+    let mut content = Vec::new();
+    reader.read_to_end(&mut content)?;
+    let context = Context::Bytes { before, after };
+    scan_bytes(&content, matcher, content.len(), context, on_event)
+}
+
+// Searches bytes already in memory, like a mapped file, `step` bytes at a
+// time so progress and stops still come while a large file is searched. A
+// regex gets every byte at once since its matches can be any size. Unlike
+// `scan` it can send the lines around the matches.
+pub fn scan_bytes(
+    bytes: &[u8],
+    matcher: &Matcher,
+    step: usize,
+    context: Context,
+    mut on_event: impl FnMut(ScanEvent) -> io::Result<ControlFlow<()>>,
+) -> io::Result<u64> {
+    let step = if matcher.is_regex() {
+        bytes.len()
+    } else {
+        step.max(1)
+    };
+    // a match starting before the end of a step can end this far after it
+    let overlap = matcher.max_len().saturating_sub(1);
+    let mut line_column = LineColumn::start();
+    let mut counted = 0;
+    // end of the last match reported, the next ones can't overlap it
+    let mut reported = 0;
+    let mut position = 0;
+    loop {
+        let next = (position + step).min(bytes.len());
+        let window = position..(next + overlap).min(bytes.len());
+        let mut result = Ok(ControlFlow::Continue(()));
+        matcher.find_all(bytes, window, |start, end| {
+            // found again by the next step
+            if start >= next && next < bytes.len() {
+                return ControlFlow::Break(());
+            }
+            if start < reported {
+                return ControlFlow::Continue(());
+            }
+            line_column.advance(&bytes[counted..start]);
+            counted = start;
+            reported = end;
+            let (snippet, lines_before, lines_after) = context.around(bytes, start, end);
+            result = on_event(ScanEvent::Found(Match {
+                start: start as u64,
+                line_column,
+                text: String::from_utf8_lossy(&bytes[start..end]).to_string(),
+                snippet,
+                lines_before,
+                lines_after,
+            }));
+            flow(&result)
+        });
+        if result?.is_break() {
+            return Ok(next as u64);
+        }
+        position = next;
+        if on_event(ScanEvent::Progress(position as u64))?.is_break() || position == bytes.len() {
+            return Ok(position as u64);
+        }
+    }
+}
+
+// an error stops the matches like a break, it is returned after them
+fn flow(result: &io::Result<ControlFlow<()>>) -> ControlFlow<()> {
+    match result {
+        Ok(flow) => *flow,
+        Err(_) => ControlFlow::Break(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // hands out at most `size` bytes per read, so matches land across reads
+    struct Trickle<'a> {
+        bytes: &'a [u8],
+        size: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            let len = self.size.min(buffer.len()).min(self.bytes.len());
+            buffer[..len].copy_from_slice(&self.bytes[..len]);
+            self.bytes = &self.bytes[len..];
+            Ok(len)
+        }
+    }
+
+    fn matches(event: ScanEvent, found: &mut Vec<Match>) -> io::Result<ControlFlow<()>> {
+        if let ScanEvent::Found(m) = event {
+            found.push(m);
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+
+    fn streamed(text: &str, matcher: &Matcher, buffer_size: usize, read_size: usize) -> Vec<u64> {
+        let mut reader = Trickle {
+            bytes: text.as_bytes(),
+            size: read_size,
+        };
+        let mut buffer = vec![0; buffer_size];
+        let mut found = Vec::new();
+        // no context, so only the matcher keeps bytes around the matches
+        scan(&mut reader, matcher, &mut buffer, 0, 0, |event| {
+            matches(event, &mut found)
+        })
+        .unwrap();
+        found.iter().map(|m| m.start).collect()
+    }
+
+    fn stepped(text: &str, matcher: &Matcher, step: usize) -> Vec<u64> {
+        let context = Context::Bytes {
+            before: 2,
+            after: 2,
+        };
+        let mut found = Vec::new();
+        scan_bytes(text.as_bytes(), matcher, step, context, |event| {
+            matches(event, &mut found)
+        })
+        .unwrap();
+        found.iter().map(|m| m.start).collect()
+    }
+
+    // the non overlapping occurrences, left to right, like the matcher
+    fn expected(text: &str, pattern: &str) -> Vec<u64> {
+        text.to_lowercase()
+            .match_indices(pattern)
+            .map(|(start, _)| start as u64)
+            .collect()
+    }
+
+    const TEXT: &str = "rust trust RUST rusty rust_x érust ação rust, xrustx\nrust";
+
+    #[test]
+    fn finds_matches_across_buffer_boundaries() {
+        let matcher = Matcher::new(&["rust".to_string()], false, false).unwrap();
+        let expected = expected(TEXT, "rust");
+        assert_eq!(expected.len(), 9);
+        for buffer_size in 4..24 {
+            for read_size in [1, 3, 7, 64] {
+                assert_eq!(streamed(TEXT, &matcher, buffer_size, read_size), expected);
+            }
+        }
+        for step in 1..16 {
+            assert_eq!(stepped(TEXT, &matcher, step), expected);
+        }
+    }
+
+    #[test]
+    fn finds_many_patterns_in_any_case() {
+        let patterns = ["Rust".to_string(), "ação".to_string()];
+        let matcher = Matcher::new(&patterns, false, false).unwrap();
+        let mut expected = [expected(TEXT, "rust"), expected(TEXT, "ação")].concat();
+        expected.sort();
+        assert_eq!(expected.len(), 10);
+        for step in 1..16 {
+            assert_eq!(stepped(TEXT, &matcher, step), expected);
+        }
+        let upper = TEXT.to_uppercase();
+        assert_eq!(stepped(&upper, &matcher, 5).len(), 10);
+    }
+
+    #[test]
+    fn reports_lines_and_columns() {
+        let matcher = Matcher::new(&["rust".to_string()], false, false).unwrap();
+        let mut reader = Trickle {
+            bytes: "ab\nçd rust\nrust".as_bytes(),
+            size: 3,
+        };
+        let mut buffer = vec![0; 8];
+        let mut found = Vec::new();
+        scan(&mut reader, &matcher, &mut buffer, 0, 0, |event| {
+            matches(event, &mut found)
+        })
+        .unwrap();
+        let positions: Vec<(u64, u64)> = found
+            .iter()
+            .map(|m| (m.line_column.line, m.line_column.column))
+            .collect();
+        assert_eq!(positions, [(2, 4), (3, 1)]);
+    }
+
+    #[test]
+    fn reports_offsets_and_whole_char_context() {
+        let matcher = Matcher::new(&["rust".to_string()], false, false).unwrap();
+        let text = "ação rust é\nrust";
+        let mut reader = Trickle {
+            bytes: text.as_bytes(),
+            size: 3,
+        };
+        let mut buffer = vec![0; 16];
+        let mut found = Vec::new();
+        scan(&mut reader, &matcher, &mut buffer, 5, 2, |event| {
+            matches(event, &mut found)
+        })
+        .unwrap();
+        let offsets: Vec<u64> = found.iter().map(|m| m.start).collect();
+        assert_eq!(offsets, [7, 15]);
+        let snippets: Vec<&str> = found.iter().map(|m| m.snippet.as_str()).collect();
+        assert_eq!(snippets, ["ão rust ", "t é\nrust"]);
+    }
+
+    #[test]
+    fn finds_regex_matches_of_any_size() {
+        let texts = |pattern: &str, text: &str| -> Vec<String> {
+            let matcher = Matcher::regex(pattern, false, false).unwrap();
+            let mut found = Vec::new();
+            scan(&mut text.as_bytes(), &matcher, &mut [0; 4], 0, 0, |event| {
+                matches(event, &mut found)
+            })
+            .unwrap();
+            found.into_iter().map(|m| m.text).collect()
+        };
+        assert_eq!(
+            texts(r"ru\w+", "Rust trust rusty"),
+            ["Rust", "rust", "rusty"]
+        );
+        // longer than the buffer and across lines
+        assert_eq!(texts(r"a.*\n.*b", "xa 1234567\n89 by"), ["a 1234567\n89 b"]);
+        // empty matches are not occurrences
+        assert_eq!(texts(r"x*", "abc"), Vec::<String>::new());
+        assert!(Matcher::regex("(", false, false).is_err());
+    }
+
+    #[test]
+    fn stops_when_told() {
+        let matcher = Matcher::new(&["rust".to_string()], false, false).unwrap();
+        let mut found = 0;
+        scan(
+            &mut TEXT.as_bytes(),
+            &matcher,
+            &mut [0; 16],
+            0,
+            0,
+            |event| {
+                if let ScanEvent::Found(_) = event {
+                    found += 1;
+                    return Ok(ControlFlow::Break(()));
+                }
+                Ok(ControlFlow::Continue(()))
+            },
+        )
+        .unwrap();
+        assert_eq!(found, 1);
+    }
+
+    #[test]
+    fn stops_stepping_when_told() {
+        let matcher = Matcher::new(&["rust".to_string()], false, false).unwrap();
+        let mut found = 0;
+        let context = Context::Bytes {
+            before: 0,
+            after: 0,
+        };
+        let read = scan_bytes(TEXT.as_bytes(), &matcher, 8, context, |event| match event {
+            ScanEvent::Found(_) => {
+                found += 1;
+                Ok(ControlFlow::Continue(()))
+            }
+            // the rest of the text is left unread
+            ScanEvent::Progress(_) => Ok(ControlFlow::Break(())),
+        })
+        .unwrap();
+        // "rust" and the one in "trust" start in the first step
+        assert_eq!((found, read), (2, 8));
+    }
+
+    #[test]
+    fn checks_whole_words_across_buffer_boundaries() {
+        let matcher = Matcher::new(&["rust".to_string()], false, true).unwrap();
+        let mut expected = expected(TEXT, "rust");
+        expected.retain(|start| {
+            let start = *start as usize;
+            is_whole_word(TEXT.as_bytes(), start, start + 4)
+        });
+        // "rust", "RUST", "rust," and the one on the last line
+        assert_eq!(expected, vec![0, 11, 43, 56]);
+        for buffer_size in 12..32 {
+            for read_size in [1, 3, 7, 64] {
+                assert_eq!(streamed(TEXT, &matcher, buffer_size, read_size), expected);
+            }
+        }
+        for step in 1..16 {
+            assert_eq!(stepped(TEXT, &matcher, step), expected);
+        }
+    }
+
+    #[test]
+    fn finds_many_patterns_with_their_case() {
+        let patterns = ["Rust".to_string(), "ação".to_string()];
+        let matcher = Matcher::new(&patterns, true, false).unwrap();
+        assert_eq!(stepped(TEXT, &matcher, 5), vec![36]);
+        let matcher = Matcher::new(&["RUST".to_string()], true, false).unwrap();
+        assert_eq!(stepped(TEXT, &matcher, 5), vec![11]);
+    }
+
+    #[test]
+    fn checks_whole_words_of_a_regex() {
+        let matcher = Matcher::regex(r"r\w+", false, true).unwrap();
+        let mut found = Vec::new();
+        let context = Context::Lines(0);
+        scan_bytes(b"rust trust r_x", &matcher, 4, context, |event| {
+            matches(event, &mut found)
+        })
+        .unwrap();
+        let texts: Vec<&str> = found.iter().map(|m| m.text.as_str()).collect();
+        assert_eq!(texts, ["rust", "r_x"]);
+    }
+}
@@ -0,0 +1,272 @@
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+};
+
+// first byte of an UTF-8 char (or an invalid byte), not a continuation byte
+pub fn is_char_start(byte: u8) -> bool {
+    byte & 0b1100_0000 != 0b1000_0000
+}
+
+// line and column (both 1-based, column in chars) of a byte in a file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineColumn {
+    pub line: u64,
+    pub column: u64,
+}
+
+impl LineColumn {
+    pub fn start() -> Self {
+        LineColumn { line: 1, column: 1 }
+    }
+
+    // moves past the bytes, counting lines and chars
+    pub fn advance(&mut self, bytes: &[u8]) {
+        match memchr::memrchr(b'\n', bytes) {
+            Some(last_newline) => {
+                self.line += memchr::memchr_iter(b'\n', bytes).count() as u64;
+                self.column = 1 + count_chars(&bytes[last_newline + 1..]);
+            }
+            None => self.column += count_chars(bytes),
+        }
+    }
+}
+
+fn count_chars(bytes: &[u8]) -> u64 {
+    bytes.iter().filter(|b| is_char_start(**b)).count() as u64
+}
+
+// Text around bytes[start..end], widened by up to `before` and `after` bytes
+// but never cutting a char in half.
+pub fn context(bytes: &[u8], start: usize, end: usize, before: usize, after: usize) -> String {
+    let mut from = start.saturating_sub(before);
+    while from < start && !is_char_start(bytes[from]) {
+        from += 1;
+    }
+    let mut to = bytes.len().min(end + after);
+    while to > end && to < bytes.len() && !is_char_start(bytes[to]) {
+        to -= 1;
+    }
+    String::from_utf8_lossy(&bytes[from..to]).to_string()
+}
+
+// same as `context`, reading only the needed bytes from the file
+pub fn read_context(
+    file: &mut File,
+    start: u64,
+    end: u64,
+    before: usize,
+    after: usize,
+) -> io::Result<String> {
+    let from = start.saturating_sub(before as u64);
+    // one byte more to know if the text after is cut at a char boundary
+    let mut buffer = vec![0; (end - from) as usize + after + 1];
+    file.seek(SeekFrom::Start(from))?;
+    let mut read = 0;
+    while read < buffer.len() {
+        match file.read(&mut buffer[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    let bytes = &buffer[..read];
+    let end = read.min((end - from) as usize);
+    let start = end.min((start - from) as usize);
+    Ok(context(bytes, start, end, before, after))
+}
+
+// longest line sent with the lines of a match, longer ones are cut
+const MAX_LINE_LENGTH: usize = 4096;
+
+// the whole lines of a match, for searches with context lines
+pub struct Lines {
+    // every line the match is on
+    pub matched: String,
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+}
+
+// The lines of bytes[start..end] and up to `count` lines before and after
+// them. Line breaks are left out and a long matched line is cut around the
+// match.
+pub fn lines(bytes: &[u8], start: usize, end: usize, count: usize) -> Lines {
+    let line_start = memchr::memrchr(b'\n', &bytes[..start]).map_or(0, |i| i + 1);
+    // a match ending with a line break stays on its line
+    let last = if end > start { end - 1 } else { end };
+    let line_end = last + memchr::memchr(b'\n', &bytes[last..]).unwrap_or(bytes.len() - last);
+    let line = trim_line_break(&bytes[line_start..line_end]);
+    let matched = if line.len() > MAX_LINE_LENGTH {
+        let room = MAX_LINE_LENGTH.saturating_sub(end - start) / 2;
+        let end = end.min(line_start + line.len());
+        context(line, start - line_start, end - line_start, room, room)
+    } else {
+        String::from_utf8_lossy(line).to_string()
+    };
+
+    let mut before = Vec::new();
+    let mut to = line_start;
+    while before.len() < count && to > 0 {
+        // `to` is right after the line break of the line before
+        let from = memchr::memrchr(b'\n', &bytes[..to - 1]).map_or(0, |i| i + 1);
+        before.push(line_text(&bytes[from..to - 1]));
+        to = from;
+    }
+    before.reverse();
+
+    let mut after = Vec::new();
+    let mut from = line_end + 1;
+    while after.len() < count && from < bytes.len() {
+        let to = from + memchr::memchr(b'\n', &bytes[from..]).unwrap_or(bytes.len() - from);
+        after.push(line_text(&bytes[from..to]));
+        from = to + 1;
+    }
+    Lines {
+        matched,
+        before,
+        after,
+    }
+}
+
+fn trim_line_break(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+// a line around the match, cut at a char boundary when it is too long
+fn line_text(line: &[u8]) -> String {
+    let line = trim_line_break(line);
+    let mut to = line.len().min(MAX_LINE_LENGTH);
+    while to < line.len() && to > 0 && !is_char_start(line[to]) {
+        to -= 1;
+    }
+    String::from_utf8_lossy(&line[..to]).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    // "ç", "ã" and "é" take two bytes each
+    const TEXT: &str = "ação rust é";
+    const RUST: (usize, usize) = (7, 11);
+
+    fn around(before: usize, after: usize) -> String {
+        context(TEXT.as_bytes(), RUST.0, RUST.1, before, after)
+    }
+
+    #[test]
+    fn widens_the_context_to_whole_chars() {
+        assert_eq!(around(0, 0), "rust");
+        assert_eq!(around(2, 2), "o rust ");
+        // starting in the middle of "ç" or "é" leaves it out
+        assert_eq!(around(4, 0), "ão rust");
+        assert_eq!(around(5, 2), "ão rust ");
+        assert_eq!(around(5, 3), "ão rust é");
+        assert_eq!(around(6, 0), "ção rust");
+        assert_eq!(around(7, 0), "ação rust");
+    }
+
+    #[test]
+    fn stops_the_context_at_the_ends_of_the_text() {
+        assert_eq!(around(100, 100), TEXT);
+        let bytes = TEXT.as_bytes();
+        assert_eq!(context(bytes, 0, 1, 10, 0), "a");
+        assert_eq!(context(bytes, 0, 3, 0, 1), "aç");
+        assert_eq!(context(bytes, 12, 14, 0, 10), "é");
+        assert_eq!(context(bytes, 12, 14, 1, 0), " é");
+    }
+
+    #[test]
+    fn reads_the_same_context_from_the_file() {
+        let path = std::env::temp_dir().join(format!("snippet-{}", std::process::id()));
+        File::create(&path)
+            .and_then(|mut file| file.write_all(TEXT.as_bytes()))
+            .unwrap();
+        let mut file = File::open(&path).unwrap();
+        let bytes = TEXT.as_bytes();
+        let ranges = [(RUST.0, RUST.1), (0, 1), (0, 3), (12, 14), (1, 5)];
+        for (start, end) in ranges {
+            for (before, after) in [(0, 0), (1, 1), (2, 3), (5, 5), (100, 100)] {
+                let read = read_context(&mut file, start as u64, end as u64, before, after);
+                assert_eq!(
+                    read.unwrap(),
+                    context(bytes, start, end, before, after),
+                    "{}..{} with {} and {}",
+                    start,
+                    end,
+                    before,
+                    after
+                );
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn counts_lines_and_chars() {
+        let mut line_column = LineColumn::start();
+        line_column.advance("ação ".as_bytes());
+        assert_eq!(line_column, LineColumn { line: 1, column: 6 });
+        line_column.advance("x\nçé\r\nab".as_bytes());
+        assert_eq!(line_column, LineColumn { line: 3, column: 3 });
+        // a char split between two reads counts once
+        let bytes = "é".as_bytes();
+        line_column.advance(&bytes[..1]);
+        line_column.advance(&bytes[1..]);
+        assert_eq!(line_column, LineColumn { line: 3, column: 4 });
+    }
+
+    const CRLF: &str = "first\r\nsecond rust\r\nthird\r\nlast";
+
+    fn lines_of(text: &str, word: &str, count: usize) -> (String, Vec<String>, Vec<String>) {
+        let start = text.find(word).unwrap();
+        let lines = lines(text.as_bytes(), start, start + word.len(), count);
+        (lines.matched, lines.before, lines.after)
+    }
+
+    #[test]
+    fn sends_whole_lines_without_their_breaks() {
+        let (matched, before, after) = lines_of(CRLF, "rust", 1);
+        assert_eq!(matched, "second rust");
+        assert_eq!(
+            (before, after),
+            (vec!["first".into()], vec!["third".into()])
+        );
+        // a match over a line break has both lines
+        let (matched, _, after) = lines_of(CRLF, "rust\r\nthird", 1);
+        assert_eq!(matched, "second rust\r\nthird");
+        assert_eq!(after, ["last"]);
+    }
+
+    #[test]
+    fn stops_at_the_first_and_last_lines() {
+        let (matched, before, after) = lines_of(CRLF, "first", 2);
+        assert_eq!(matched, "first");
+        assert!(before.is_empty());
+        assert_eq!(after, ["second rust", "third"]);
+        let (matched, before, after) = lines_of(CRLF, "last", 5);
+        assert_eq!(matched, "last");
+        assert_eq!(before, ["first", "second rust", "third"]);
+        assert!(after.is_empty());
+        // the break ending the file starts no other line
+        let (_, _, after) = lines_of("a\nrust\n", "rust", 1);
+        assert!(after.is_empty());
+        let (_, before, _) = lines_of("\nrust", "rust", 1);
+        assert_eq!(before, [""]);
+    }
+
+    #[test]
+    fn cuts_long_lines_at_whole_chars() {
+        let long = "é".repeat(MAX_LINE_LENGTH);
+        let text = format!("{}\n{} rust {}\n{}", long, long, long, long);
+        let (matched, before, after) = lines_of(&text, "rust", 1);
+        // the matched line is cut around the match
+        assert!(matched.len() <= MAX_LINE_LENGTH);
+        assert!(matched.contains(" rust "));
+        assert!(!matched.contains(char::REPLACEMENT_CHARACTER));
+        for line in before.iter().chain(&after) {
+            assert_eq!(line.len(), MAX_LINE_LENGTH);
+            assert!(line.chars().all(|c| c == 'é'));
+        }
+    }
+}